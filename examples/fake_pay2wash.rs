@@ -0,0 +1,399 @@
+//! A fake pay2wash server for exercising `Pay2WashClient` against real HTTP.
+//!
+//! ```sh
+//! cargo run --example fake_pay2wash
+//!
+//! PAY2WASH_BASE_URL=http://localhost:9092 PAY2WASH_TENANT= \
+//! PAY2WASH_EMAIL=tenant@example.com PAY2WASH_PASSWORD=hunter2 cargo run
+//! ```
+//!
+//! The server can be scripted at runtime through the `/_fake` routes:
+//!
+//! - `POST /_fake/machine_statuses` queues a `machine_statuses` JSON response, queued
+//!   responses are served in order with the last one being repeated
+//! - `POST /_fake/expire_sessions` de-authenticates every session, causing the next
+//!   `machine_statuses` request to be redirected to `/login`
+//! - `POST /_fake/machines` replaces the machines listed on `/home` with a JSON object of
+//!   machine ids to names
+
+#![forbid(unsafe_code)]
+#![deny(clippy::unwrap_used, clippy::as_conversions)]
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Form, Json, Router, Server,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::{info, warn, Level};
+
+const SESSION_COOKIE: &str = "pay2wash_session";
+
+const USER_TOKEN: u32 = 4242;
+const LOCATION: &str = "17";
+const MACHINES: &[(&str, &str)] = &[("101", "W1"), ("102", "W2"), ("201", "D1"), ("202", "D2")];
+
+#[derive(Debug)]
+struct Fake {
+    email: String,
+    password: String,
+    state: Mutex<FakeState>,
+}
+
+#[derive(Debug, Default)]
+struct FakeState {
+    next_token: u64,
+    sessions: HashMap<String, Session>,
+    machine_statuses: VecDeque<Value>,
+    /// Machines listed on `/home` by id, [`MACHINES`] until replaced
+    machines: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Debug)]
+struct Session {
+    csrf_token: String,
+    authenticated: bool,
+}
+
+impl Fake {
+    fn state(&self) -> MutexGuard<'_, FakeState> {
+        self.state.lock().expect("fake state mutex should not be poisoned")
+    }
+}
+
+impl FakeState {
+    fn token(&mut self) -> String {
+        self.next_token += 1;
+
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time should only move forwards")
+            .subsec_nanos();
+
+        format!("{:016x}{nanos:08x}", self.next_token)
+    }
+
+    /// Get the session referenced by the request's cookie, or start a new one
+    fn session(&mut self, headers: &HeaderMap) -> (String, &mut Session) {
+        let session_id = session_cookie(headers)
+            .filter(|session_id| self.sessions.contains_key(*session_id))
+            .map(str::to_owned);
+
+        let session_id = match session_id {
+            Some(session_id) => session_id,
+            None => {
+                let session_id = self.token();
+                let csrf_token = self.token();
+
+                self.sessions.insert(
+                    session_id.clone(),
+                    Session {
+                        csrf_token,
+                        authenticated: false,
+                    },
+                );
+
+                session_id
+            }
+        };
+
+        let session = self
+            .sessions
+            .get_mut(&session_id)
+            .expect("session should have just been inserted");
+
+        (session_id, session)
+    }
+
+    fn is_authenticated(&self, headers: &HeaderMap) -> bool {
+        session_cookie(headers)
+            .and_then(|session_id| self.sessions.get(session_id))
+            .is_some_and(|session| session.authenticated)
+    }
+}
+
+fn session_cookie(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find_map(|(name, value)| (name == SESSION_COOKIE).then_some(value))
+}
+
+fn with_session_cookie(session_id: &str, response: impl IntoResponse) -> Response {
+    let mut response = response.into_response();
+
+    response.headers_mut().insert(
+        header::SET_COOKIE,
+        HeaderValue::from_str(&format!("{SESSION_COOKIE}={session_id}; Path=/; HttpOnly"))
+            .expect("session id should be a valid header value"),
+    );
+
+    response
+}
+
+fn page(csrf_token: &str, user_token: &str, body: &str) -> Html<String> {
+    Html(format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+    <meta name="csrf-token" content="{csrf_token}">
+    <meta name="user-token" content="{user_token}">
+    <title>Pay2Wash</title>
+</head>
+<body>
+{body}
+</body>
+</html>"#
+    ))
+}
+
+fn default_machine_statuses() -> Value {
+    MACHINES
+        .iter()
+        .map(|(id, _)| {
+            (
+                String::from(*id),
+                json!({
+                    "running": false,
+                    "starter": 0,
+                    "reserved": false,
+                    "reserver": 0,
+                    "in_maintenance": 0,
+                    "remaining_time": "00:00",
+                    "gateway_offline": 0,
+                    "remaining_time_is_from_machine": 0,
+                    "controller_logic": 0,
+                }),
+            )
+        })
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    tracing_subscriber::fmt().with_max_level(Level::DEBUG).init();
+
+    let port = std::env::var("FAKE_PAY2WASH_PORT")
+        .ok()
+        .and_then(|port| port.parse().ok())
+        .unwrap_or(9092);
+
+    let fake = Arc::new(Fake {
+        email: std::env::var("FAKE_PAY2WASH_EMAIL")
+            .unwrap_or_else(|_| String::from("tenant@example.com")),
+        password: std::env::var("FAKE_PAY2WASH_PASSWORD")
+            .unwrap_or_else(|_| String::from("hunter2")),
+        state: Mutex::default(),
+    });
+
+    let router = Router::new()
+        .route("/", get(root))
+        .route("/login", get(login_form).post(login))
+        .route("/home", get(home))
+        .route("/logout", get(logout))
+        .route("/machine_statuses/:location", get(machine_statuses))
+        .route("/_fake/machine_statuses", post(queue_machine_statuses))
+        .route("/_fake/expire_sessions", post(expire_sessions))
+        .route("/_fake/machines", post(replace_machines))
+        .with_state(fake.clone());
+
+    info!(
+        email = fake.email,
+        password = fake.password,
+        "Starting fake pay2wash server on http://localhost:{port}"
+    );
+
+    Server::bind(&SocketAddrV4::new(Ipv4Addr::LOCALHOST, port).into())
+        .serve(router.into_make_service())
+        .await
+        .expect("fake pay2wash server ran into a problem");
+}
+
+async fn root(State(fake): State<Arc<Fake>>, headers: HeaderMap) -> Redirect {
+    if fake.state().is_authenticated(&headers) {
+        Redirect::to("/home")
+    } else {
+        Redirect::to("/login")
+    }
+}
+
+async fn login_form(State(fake): State<Arc<Fake>>, headers: HeaderMap) -> Response {
+    let mut state = fake.state();
+    let (session_id, session) = state.session(&headers);
+
+    if session.authenticated {
+        return with_session_cookie(&session_id, Redirect::to("/home"));
+    }
+
+    let body = format!(
+        r#"<form method="POST" action="/login">
+    <input type="hidden" name="_token" value="{}">
+    <input type="email" name="email">
+    <input type="password" name="password">
+</form>"#,
+        session.csrf_token
+    );
+
+    with_session_cookie(&session_id, page(&session.csrf_token, "", &body))
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginForm {
+    _token: String,
+    email: String,
+    password: String,
+}
+
+async fn login(
+    State(fake): State<Arc<Fake>>,
+    headers: HeaderMap,
+    Form(form): Form<LoginForm>,
+) -> Response {
+    let mut state = fake.state();
+    let (session_id, session) = state.session(&headers);
+
+    if form._token != session.csrf_token {
+        warn!("login form submitted with a mismatched csrf token");
+
+        // Laravel's response to a csrf mismatch
+        return with_session_cookie(
+            &session_id,
+            StatusCode::from_u16(419)
+                .expect("419 should be a valid status code")
+                .into_response(),
+        );
+    }
+
+    if form.email != fake.email || form.password != fake.password {
+        warn!(email = form.email, "login form submitted with bad credentials");
+
+        return with_session_cookie(&session_id, Redirect::to("/login"));
+    }
+
+    info!(email = form.email, "session authenticated");
+
+    session.authenticated = true;
+
+    with_session_cookie(&session_id, Redirect::to("/"))
+}
+
+async fn home(State(fake): State<Arc<Fake>>, headers: HeaderMap) -> Response {
+    let mut state = fake.state();
+    let machines = state.machines.clone();
+    let (session_id, session) = state.session(&headers);
+
+    if !session.authenticated {
+        return with_session_cookie(&session_id, Redirect::to("/login"));
+    }
+
+    let machines = machines.unwrap_or_else(|| {
+        MACHINES
+            .iter()
+            .map(|&(id, name)| (String::from(id), String::from(name)))
+            .collect()
+    });
+
+    let machines = machines
+        .iter()
+        .map(|(id, name)| {
+            format!(
+                r#"    <div class="machine">
+        <input type="hidden" class="machine_pk" value="{id}">
+        <span class="js-reservation">
+            {name}
+        </span>
+    </div>"#
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let body = format!(r#"<input type="hidden" id="location" value="{LOCATION}">
+{machines}"#);
+
+    with_session_cookie(
+        &session_id,
+        page(&session.csrf_token, &USER_TOKEN.to_string(), &body),
+    )
+}
+
+async fn logout(State(fake): State<Arc<Fake>>, headers: HeaderMap) -> Response {
+    let mut state = fake.state();
+    let (session_id, session) = state.session(&headers);
+
+    session.authenticated = false;
+
+    info!("session logged out");
+
+    with_session_cookie(&session_id, Redirect::to("/"))
+}
+
+async fn machine_statuses(
+    State(fake): State<Arc<Fake>>,
+    headers: HeaderMap,
+    Path(location): Path<String>,
+) -> Response {
+    let mut state = fake.state();
+
+    if !state.is_authenticated(&headers) {
+        return Redirect::to("/login").into_response();
+    }
+
+    if location != LOCATION {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let statuses = if state.machine_statuses.len() > 1 {
+        state.machine_statuses.pop_front()
+    } else {
+        state.machine_statuses.front().cloned()
+    };
+
+    Json(statuses.unwrap_or_else(default_machine_statuses)).into_response()
+}
+
+async fn queue_machine_statuses(
+    State(fake): State<Arc<Fake>>,
+    Json(statuses): Json<Value>,
+) -> StatusCode {
+    fake.state().machine_statuses.push_back(statuses);
+
+    StatusCode::NO_CONTENT
+}
+
+async fn expire_sessions(State(fake): State<Arc<Fake>>) -> StatusCode {
+    let mut state = fake.state();
+
+    for session in state.sessions.values_mut() {
+        session.authenticated = false;
+    }
+
+    info!("expired all sessions");
+
+    StatusCode::NO_CONTENT
+}
+
+async fn replace_machines(
+    State(fake): State<Arc<Fake>>,
+    Json(machines): Json<BTreeMap<String, String>>,
+) -> StatusCode {
+    info!(?machines, "replaced machines");
+
+    fake.state().machines = Some(machines);
+
+    StatusCode::NO_CONTENT
+}
@@ -0,0 +1,110 @@
+//! A webhook receiver that verifies and logs every delivery, for exercising webhooks locally.
+//!
+//! ```sh
+//! cargo run --example webhook_receiver
+//! ```
+//!
+//! with a webhooks file like
+//!
+//! ```toml
+//! [[webhook]]
+//! url = "http://localhost:9093/"
+//! secret = "hunter2"
+//! ```
+//!
+//! Set `WEBHOOK_RECEIVER_FAIL` to respond with a 500 to every delivery, to exercise retries.
+
+#![forbid(unsafe_code)]
+#![deny(clippy::unwrap_used, clippy::as_conversions)]
+
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::Arc,
+};
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    routing::post,
+    Router, Server,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tracing::{info, warn, Level};
+
+#[derive(Debug)]
+struct Receiver {
+    secret: String,
+    fail: bool,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    tracing_subscriber::fmt().with_max_level(Level::DEBUG).init();
+
+    let port = std::env::var("WEBHOOK_RECEIVER_PORT")
+        .ok()
+        .and_then(|port| port.parse().ok())
+        .unwrap_or(9093);
+
+    let receiver = Arc::new(Receiver {
+        secret: std::env::var("WEBHOOK_RECEIVER_SECRET")
+            .unwrap_or_else(|_| String::from("hunter2")),
+        fail: std::env::var_os("WEBHOOK_RECEIVER_FAIL").is_some(),
+    });
+
+    let router = Router::new()
+        .route("/", post(receive))
+        .with_state(receiver.clone());
+
+    info!(
+        secret = receiver.secret,
+        fail = receiver.fail,
+        "Starting webhook receiver on http://localhost:{port}"
+    );
+
+    Server::bind(&SocketAddrV4::new(Ipv4Addr::LOCALHOST, port).into())
+        .serve(router.into_make_service())
+        .await
+        .expect("webhook receiver ran into a problem");
+}
+
+async fn receive(
+    State(receiver): State<Arc<Receiver>>,
+    headers: HeaderMap,
+    body: String,
+) -> StatusCode {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+    };
+
+    let timestamp = header("x-pain2wash-timestamp");
+    let event = header("x-pain2wash-event");
+
+    let Ok(signature) = hex::decode(header("x-pain2wash-signature")) else {
+        warn!(event, "signature is not hex encoded");
+        return StatusCode::UNAUTHORIZED;
+    };
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(receiver.secret.as_bytes())
+        .expect("hmac should accept keys of any length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+
+    if mac.verify_slice(&signature).is_err() {
+        warn!(event, timestamp, body, "signature does not match");
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    info!(event, timestamp, body, "received webhook");
+
+    if receiver.fail {
+        StatusCode::INTERNAL_SERVER_ERROR
+    } else {
+        StatusCode::NO_CONTENT
+    }
+}
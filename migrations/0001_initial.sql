@@ -0,0 +1,39 @@
+-- Timestamps are UNIX timestamps in seconds, a NULL start means the machine was
+-- already occupied when the scraper first saw it
+
+CREATE TABLE cycles (
+    id INTEGER PRIMARY KEY,
+    location TEXT NOT NULL,
+    machine TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    started_at INTEGER,
+    ended_at INTEGER,
+    starter INTEGER NOT NULL,
+    initial_remaining_time INTEGER,
+    remaining_time_is_from_machine INTEGER
+);
+
+CREATE INDEX cycles_machine ON cycles (location, machine, started_at);
+
+CREATE TABLE reservations (
+    id INTEGER PRIMARY KEY,
+    location TEXT NOT NULL,
+    machine TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    reserved_at INTEGER,
+    released_at INTEGER,
+    reserver INTEGER NOT NULL
+);
+
+CREATE INDEX reservations_machine ON reservations (location, machine, reserved_at);
+
+CREATE TABLE maintenance_windows (
+    id INTEGER PRIMARY KEY,
+    location TEXT NOT NULL,
+    machine TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    entered_at INTEGER,
+    left_at INTEGER
+);
+
+CREATE INDEX maintenance_windows_machine ON maintenance_windows (location, machine, entered_at);
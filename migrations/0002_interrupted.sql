@@ -0,0 +1,6 @@
+-- Rows the scraper stopped before seeing the end of are marked interrupted on the
+-- next startup, their end stays NULL and is never filled in after that
+
+ALTER TABLE cycles ADD COLUMN interrupted INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE reservations ADD COLUMN interrupted INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE maintenance_windows ADD COLUMN interrupted INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,218 @@
+use axum::{
+    extract::{FromRef, Path, Query, State},
+    http::StatusCode,
+    response::sse::{Event, KeepAlive, Sse},
+    routing::get,
+    Json, Router,
+};
+use futures_util::{stream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+
+use crate::{
+    events::Events,
+    forecast::{Forecast, Forecaster},
+    occupancy::{Occupancy, PrefixOccupancy, MINUTES_PER_QUARTER},
+    pay2wash::model::MachineKind,
+    shutdown::Shutdown,
+    status::{MachineReport, StatusBoard},
+    time::unix_timestamp,
+};
+
+#[derive(Debug, Clone, FromRef)]
+pub struct ApiState {
+    pub status_board: StatusBoard,
+    pub events: Events,
+    pub occupancy: Occupancy,
+    pub forecaster: Forecaster,
+    pub shutdown: Shutdown,
+}
+
+pub fn router(state: ApiState) -> Router {
+    Router::new()
+        .route("/machines", get(machines))
+        .route("/machines/:name", get(machine))
+        .route("/events", get(events))
+        .route("/stats/occupancy", get(occupancy))
+        .route("/forecast", get(forecast))
+        .with_state(state)
+}
+
+#[derive(Debug, Deserialize)]
+struct MachineFilter {
+    location: Option<String>,
+}
+
+impl MachineFilter {
+    fn matches(&self, report: &MachineReport) -> bool {
+        self.location
+            .as_ref()
+            .is_none_or(|location| &report.location == location)
+    }
+}
+
+#[tracing::instrument(skip_all)]
+#[axum::debug_handler]
+async fn machines(
+    State(status_board): State<StatusBoard>,
+    Query(filter): Query<MachineFilter>,
+) -> Json<Vec<MachineReport>> {
+    Json(
+        status_board
+            .reports()
+            .values()
+            .filter(|report| filter.matches(report))
+            .cloned()
+            .collect(),
+    )
+}
+
+#[tracing::instrument(skip(status_board, filter))]
+#[axum::debug_handler]
+async fn machine(
+    State(status_board): State<StatusBoard>,
+    Path(name): Path<String>,
+    Query(filter): Query<MachineFilter>,
+) -> Result<Json<MachineReport>, StatusCode> {
+    status_board
+        .reports()
+        .values()
+        .find(|report| report.name == name && filter.matches(report))
+        .cloned()
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Stream every machine event, named after its type, eg. `state_change` with the `previous` and
+/// `current` state of a machine
+#[tracing::instrument(skip_all)]
+#[axum::debug_handler(state = ApiState)]
+async fn events(
+    State(events): State<Events>,
+    State(mut shutdown): State<Shutdown>,
+) -> Sse<impl Stream<Item = Result<Event, serde_json::Error>>> {
+    let machine_events = stream::unfold(events.subscribe(), |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => return Some((event, receiver)),
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(skipped, "event stream fell behind, skipping machine events");
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+
+    let events = machine_events
+        .map(|event| {
+            serde_json::to_string(&event)
+                .map(|data| Event::default().event(event.event.as_str()).data(data))
+        })
+        // Long lived streams would otherwise hold up the graceful shutdown of the server
+        .take_until(async move { shutdown.wait().await });
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+#[derive(Debug, Deserialize)]
+struct OccupancyQuery {
+    /// Minutes to shift the hours of the week by, eg. 120 for Amsterdam in summer or 330 for India
+    #[serde(default)]
+    utc_offset: i64,
+    /// Every location if unset
+    location: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct OccupancyResponse {
+    lookback_hours: i64,
+    utc_offset: i64,
+    prefixes: Vec<PrefixOccupancy>,
+}
+
+/// The fraction of time machines were busy per location, machine name prefix and hour of the week
+#[tracing::instrument(skip(occupancy))]
+#[axum::debug_handler]
+async fn occupancy(
+    State(occupancy): State<Occupancy>,
+    Query(query): Query<OccupancyQuery>,
+) -> Result<Json<OccupancyResponse>, (StatusCode, &'static str)> {
+    if !(-12 * 60..=14 * 60).contains(&query.utc_offset) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "utc_offset must be between -720 and 840 minutes",
+        ));
+    }
+
+    if query.utc_offset % MINUTES_PER_QUARTER != 0 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "utc_offset must be a multiple of 15 minutes",
+        ));
+    }
+
+    Ok(Json(OccupancyResponse {
+        lookback_hours: occupancy.lookback_hours(),
+        utc_offset: query.utc_offset,
+        prefixes: occupancy.summary(query.utc_offset, query.location.as_deref()),
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct ForecastQuery {
+    /// Every kind of machine if unset
+    kind: Option<MachineKind>,
+    /// The number of machines that need to be free
+    #[serde(default = "default_forecast_count")]
+    count: usize,
+    location: Option<String>,
+}
+
+fn default_forecast_count() -> usize {
+    1
+}
+
+/// The earliest time at least `count` machines of each kind are expected to be free
+#[tracing::instrument(skip(status_board, forecaster))]
+#[axum::debug_handler(state = ApiState)]
+async fn forecast(
+    State(status_board): State<StatusBoard>,
+    State(forecaster): State<Forecaster>,
+    Query(query): Query<ForecastQuery>,
+) -> Result<Json<Vec<Forecast>>, (StatusCode, &'static str)> {
+    if query.count == 0 {
+        return Err((StatusCode::BAD_REQUEST, "count must be at least 1"));
+    }
+
+    let kinds = match query.kind {
+        Some(kind) => vec![kind],
+        None => vec![MachineKind::Washer, MachineKind::Dryer],
+    };
+
+    let filter = MachineFilter {
+        location: query.location,
+    };
+
+    let reports = status_board.reports();
+    // Time is taken from the scrapes rather than the clock so forecasts of replays line up too
+    let now = reports
+        .values()
+        .map(|report| report.updated_at)
+        .max()
+        .unwrap_or_else(unix_timestamp);
+
+    Ok(Json(
+        kinds
+            .into_iter()
+            .map(|kind| {
+                forecaster.forecast(
+                    reports.values().filter(|report| filter.matches(report)),
+                    kind,
+                    query.count,
+                    now,
+                )
+            })
+            .collect(),
+    ))
+}
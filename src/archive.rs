@@ -0,0 +1,254 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::Context;
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{debug, error, info, warn};
+
+use crate::shutdown::Shutdown;
+
+const FILE_PREFIX: &str = "snapshots-";
+const FILE_SUFFIX: &str = ".jsonl.gz";
+
+/// A raw response from pay2wash, exactly as it was received
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// UNIX timestamp of when the response was received
+    pub captured_at: i64,
+    pub kind: SnapshotKind,
+    /// The final url of the response, after any redirects
+    pub url: String,
+    pub status: u16,
+    pub body: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SnapshotKind {
+    /// A login or home page
+    Html,
+    MachineStatuses,
+}
+
+/// Handle used to hand snapshots to the [`ArchiveWriter`], cheap to clone for every client
+#[derive(Debug, Clone)]
+pub struct Archive(mpsc::Sender<Snapshot>);
+
+impl Archive {
+    /// Queue `snapshot` to be written, dropping it if the writer cannot keep up
+    pub fn capture(&self, snapshot: Snapshot) {
+        match self.0.try_send(snapshot) {
+            Ok(()) => {}
+            Err(TrySendError::Full(snapshot)) => {
+                warn!(url = snapshot.url, "archive fell behind, dropping snapshot");
+            }
+            // The writer only stops after shutdown, when losing the last few snapshots is fine
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+}
+
+/// Writes snapshots to gzip compressed JSONL files in a directory, starting a new file
+/// once the current one grows past `max_file_bytes` and keeping at most `max_files`
+#[derive(Debug)]
+pub struct ArchiveWriter {
+    receiver: mpsc::Receiver<Snapshot>,
+    files: ArchiveFiles,
+}
+
+#[derive(Debug)]
+struct ArchiveFiles {
+    directory: PathBuf,
+    max_file_bytes: u64,
+    max_files: usize,
+    current: Option<File>,
+}
+
+pub fn archive(
+    directory: PathBuf,
+    max_file_bytes: u64,
+    max_files: usize,
+) -> color_eyre::Result<(Archive, ArchiveWriter)> {
+    fs::create_dir_all(&directory).wrap_err_with(|| {
+        format!("failed to create archive directory {}", directory.display())
+    })?;
+
+    let (sender, receiver) = mpsc::channel(64);
+
+    Ok((
+        Archive(sender),
+        ArchiveWriter {
+            receiver,
+            files: ArchiveFiles {
+                directory,
+                max_file_bytes,
+                max_files,
+                current: None,
+            },
+        },
+    ))
+}
+
+impl ArchiveWriter {
+    /// Write every captured snapshot until shutdown
+    pub async fn run(self, mut shutdown: Shutdown) -> color_eyre::Result<()> {
+        let ArchiveWriter {
+            mut receiver,
+            mut files,
+        } = self;
+
+        loop {
+            let snapshot = tokio::select! {
+                snapshot = receiver.recv() => snapshot,
+                () = shutdown.wait() => break,
+            };
+
+            let Some(snapshot) = snapshot else { break };
+
+            files = files.write_blocking(snapshot).await?;
+        }
+
+        // Anything captured during the final scrape is still worth keeping
+        while let Ok(snapshot) = receiver.try_recv() {
+            files = files.write_blocking(snapshot).await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl ArchiveFiles {
+    /// [`ArchiveFiles::write`] off the async runtime
+    async fn write_blocking(mut self, snapshot: Snapshot) -> color_eyre::Result<Self> {
+        tokio::task::spawn_blocking(move || {
+            // A full disk should not take the exporter down with it
+            if let Err(error) = self.write(&snapshot) {
+                error!(?error, "failed to archive snapshot");
+            }
+
+            self
+        })
+        .await
+        .wrap_err("archive writer panicked")
+    }
+
+    fn write(&mut self, snapshot: &Snapshot) -> color_eyre::Result<()> {
+        let mut line = serde_json::to_vec(snapshot).wrap_err("failed to serialize snapshot")?;
+        line.push(b'\n');
+
+        let file = match self.current.take() {
+            Some(file) if file.metadata()?.len() < self.max_file_bytes => file,
+            _ => self.rotate(snapshot.captured_at)?,
+        };
+
+        // Every line is a gzip member of its own, so a crash can at most lose the line being
+        // written and the files stay readable as a whole by any gzip decoder
+        let mut encoder = GzEncoder::new(&file, Compression::default());
+        encoder.write_all(&line)?;
+        encoder.finish()?;
+
+        self.current = Some(file);
+
+        Ok(())
+    }
+
+    /// Start a new file, removing the oldest files past `max_files`
+    fn rotate(&self, captured_at: i64) -> color_eyre::Result<File> {
+        let path = self
+            .directory
+            .join(format!("{FILE_PREFIX}{captured_at}{FILE_SUFFIX}"));
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .wrap_err_with(|| format!("failed to open archive file {}", path.display()))?;
+
+        info!(path = %path.display(), "started new archive file");
+
+        let files = archive_files(&self.directory)?;
+
+        for old in files.iter().take(files.len().saturating_sub(self.max_files)) {
+            debug!(path = %old.display(), "removing old archive file");
+
+            fs::remove_file(old)
+                .wrap_err_with(|| format!("failed to remove archive file {}", old.display()))?;
+        }
+
+        Ok(file)
+    }
+}
+
+/// Every archive file in `directory`, oldest first
+fn archive_files(directory: &Path) -> color_eyre::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+
+    for entry in fs::read_dir(directory)
+        .wrap_err_with(|| format!("failed to read archive directory {}", directory.display()))?
+    {
+        let path = entry?.path();
+
+        let is_archive = path.file_name().and_then(|name| name.to_str()).is_some_and(|name| {
+            name.starts_with(FILE_PREFIX) && name.ends_with(FILE_SUFFIX)
+        });
+
+        if is_archive {
+            files.push(path);
+        }
+    }
+
+    // Sorting on the timestamp rather than the name keeps the order right across digit counts
+    files.sort_by_key(|path| {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(FILE_PREFIX)?.strip_suffix(FILE_SUFFIX))
+            .and_then(|timestamp| timestamp.parse::<i64>().ok())
+    });
+
+    Ok(files)
+}
+
+/// Every snapshot archived in `directory`, oldest first
+///
+/// Unreadable lines are skipped, and a file that ends in a partially written line is read up
+/// to that line.
+pub fn read_archive(directory: &Path) -> color_eyre::Result<impl Iterator<Item = Snapshot>> {
+    let files = archive_files(directory)?;
+
+    Ok(files.into_iter().flat_map(|path| {
+        let lines = match File::open(&path) {
+            Ok(file) => Some(BufReader::new(MultiGzDecoder::new(file)).lines()),
+            Err(error) => {
+                warn!(?error, path = %path.display(), "failed to open archive file");
+                None
+            }
+        };
+
+        lines
+            .into_iter()
+            .flatten()
+            .map_while({
+                let path = path.clone();
+
+                move |line| match line {
+                    Ok(line) => Some(line),
+                    Err(error) => {
+                        warn!(?error, path = %path.display(), "stopped reading archive file early");
+                        None
+                    }
+                }
+            })
+            .filter_map(move |line| match serde_json::from_str(&line) {
+                Ok(snapshot) => Some(snapshot),
+                Err(error) => {
+                    warn!(?error, path = %path.display(), "skipping invalid archived snapshot");
+                    None
+                }
+            })
+    }))
+}
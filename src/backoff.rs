@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Exponential backoff with jitter, the jitter keeps multiple retrying clients
+/// from synchronizing their requests.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// The delay before the next attempt, somewhere between half and all of
+    /// `initial * 2^attempt` capped at `max`
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self
+            .initial
+            .checked_mul(2_u32.saturating_pow(self.attempt))
+            .map_or(self.max, |delay| delay.min(self.max));
+
+        self.attempt = self.attempt.saturating_add(1);
+
+        let half = delay / 2;
+
+        half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+    }
+
+    /// The number of delays handed out since the last reset
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
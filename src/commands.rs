@@ -0,0 +1,309 @@
+use std::fmt::Write;
+
+use clap::ValueEnum;
+use color_eyre::eyre::{ensure, Context};
+use tracing::{error, warn};
+
+use crate::{
+    config::Account,
+    pay2wash::{
+        model::{MachineState, NumberBool},
+        AuthenticatedSession, Pay2WashClient,
+    },
+    status::{MachineReport, StatusBoard},
+    time::unix_timestamp,
+    MachineStateLabel,
+};
+
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+pub enum OutputFormat {
+    /// Aligned columns for reading in a terminal
+    #[default]
+    Table,
+    /// The same reports as `/api/v1/machines`
+    Json,
+    Csv,
+}
+
+/// Log in to every account once and print the status of all their machines in `format`
+pub async fn status(accounts: Vec<Account>, format: OutputFormat) -> color_eyre::Result<()> {
+    let status_board = StatusBoard::default();
+
+    for account in &accounts {
+        publish_statuses(&account.client, &status_board)
+            .await
+            .wrap_err_with(|| format!("failed to get the machine statuses of {}", account.email))?;
+    }
+
+    // Sorted by location and name
+    let reports: Vec<MachineReport> = status_board.reports().values().cloned().collect();
+
+    let output = match format {
+        OutputFormat::Table => table(
+            [
+                "LOCATION",
+                "NAME",
+                "KIND",
+                "STATE",
+                "REMAINING",
+                "USER",
+                "GATEWAY",
+            ],
+            reports.iter().map(|report| {
+                let row = Row::from(report);
+
+                [
+                    row.location,
+                    row.name,
+                    row.kind,
+                    row.state,
+                    row.remaining_time
+                        .map(|seconds| format!("{} min", seconds.div_ceil(60)))
+                        .unwrap_or_else(|| String::from("-")),
+                    row.user
+                        .map_or_else(|| String::from("-"), |user| user.to_string()),
+                    String::from(if row.gateway_offline {
+                        "offline"
+                    } else {
+                        "online"
+                    }),
+                ]
+            }),
+        ),
+        OutputFormat::Json => {
+            let mut output =
+                serde_json::to_string_pretty(&reports).wrap_err("failed to serialize reports")?;
+            output.push('\n');
+            output
+        }
+        OutputFormat::Csv => {
+            let mut output =
+                String::from("location,name,kind,state,remaining_time,user,gateway_offline\n");
+
+            for report in &reports {
+                let row = Row::from(report);
+
+                let fields = [
+                    row.location,
+                    row.name,
+                    row.kind,
+                    row.state,
+                    row.remaining_time
+                        .map(|seconds| seconds.to_string())
+                        .unwrap_or_default(),
+                    row.user.map(|user| user.to_string()).unwrap_or_default(),
+                    row.gateway_offline.to_string(),
+                ];
+
+                let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+
+                output.push_str(&fields.join(","));
+                output.push('\n');
+            }
+
+            output
+        }
+    };
+
+    print!("{output}");
+
+    Ok(())
+}
+
+/// Log in to every account once and print the location and machine mappings pay2wash shows
+/// it, carrying on with the other accounts when one fails
+pub async fn login_check(accounts: Vec<Account>) -> color_eyre::Result<()> {
+    let mut failed = 0;
+
+    for (index, account) in accounts.iter().enumerate() {
+        if index > 0 {
+            println!();
+        }
+
+        println!("Account: {}", account.email);
+
+        match check_login(&account.client).await {
+            Ok(output) => print!("{output}"),
+            Err(error) => {
+                println!("Failed to log in");
+                error!(account = account.email, ?error, "login check failed");
+
+                failed += 1;
+            }
+        }
+    }
+
+    ensure!(
+        failed == 0,
+        "{failed} of {} accounts failed to log in",
+        accounts.len()
+    );
+
+    Ok(())
+}
+
+/// The user, location and machines pay2wash shows the account of `client`
+async fn check_login(client: &Pay2WashClient) -> color_eyre::Result<String> {
+    let session = client.authenticate().await.wrap_err("failed to log in")?;
+
+    let mut machines: Vec<_> = session.machine_mappings.iter().collect();
+    machines.sort_by(|(_, a), (_, b)| a.name.cmp(&b.name));
+
+    let mut output = String::new();
+
+    writeln!(
+        output,
+        "Logged in as user {}",
+        u32::from(session.user_token)
+    )?;
+    writeln!(output, "Location: {}", session.location)?;
+    writeln!(output, "Machines:")?;
+
+    output.push_str(&table(
+        ["ID", "NAME", "KIND"],
+        machines.iter().map(|(id, machine)| {
+            [
+                (*id).clone(),
+                machine.name.clone(),
+                String::from(machine.kind.as_str()),
+            ]
+        }),
+    ));
+
+    logout(client, session).await;
+
+    Ok(output)
+}
+
+async fn logout(client: &Pay2WashClient, session: AuthenticatedSession) {
+    if let Err(error) = client.logout(session).await {
+        warn!(?error, "failed to log out");
+    }
+}
+
+/// Log in as `client` and publish the statuses of its location to `status_board`
+async fn publish_statuses(
+    client: &Pay2WashClient,
+    status_board: &StatusBoard,
+) -> color_eyre::Result<()> {
+    let mut session = client.authenticate().await.wrap_err("failed to log in")?;
+
+    let result = client.get_machine_statuses(&mut session).await.map(|statuses| {
+        status_board.publish(
+            &session.location,
+            statuses.iter().map(|(name, status)| (name.as_str(), status)),
+            unix_timestamp(),
+        );
+    });
+
+    logout(client, session).await;
+
+    result.wrap_err("failed to get machine statuses")
+}
+
+/// The columns shared by the table and CSV output
+struct Row {
+    location: String,
+    name: String,
+    kind: String,
+    state: String,
+    /// Seconds, only for running machines
+    remaining_time: Option<u64>,
+    /// Whoever started or reserved the machine
+    user: Option<u32>,
+    gateway_offline: bool,
+}
+
+impl From<&MachineReport> for Row {
+    fn from(report: &MachineReport) -> Self {
+        let (remaining_time, user) = match report.state {
+            MachineState::Running {
+                starter,
+                remaining_time,
+                ..
+            } => (
+                Some(remaining_time.into_inner().as_secs()),
+                Some(u32::from(starter)),
+            ),
+            MachineState::Reserved { reserver } => (None, Some(u32::from(reserver))),
+            MachineState::Maintenance | MachineState::Idle => (None, None),
+        };
+
+        Self {
+            location: report.location.clone(),
+            name: report.name.clone(),
+            kind: String::from(report.kind.as_str()),
+            state: String::from(MachineStateLabel::from(&report.state).as_str()),
+            remaining_time,
+            user,
+            gateway_offline: matches!(report.gateway_offline, NumberBool::True),
+        }
+    }
+}
+
+/// Left align every column to its widest cell
+fn table<const N: usize>(header: [&str; N], rows: impl Iterator<Item = [String; N]>) -> String {
+    let rows: Vec<[String; N]> = rows.collect();
+
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut output = String::new();
+
+    let header = header.map(String::from);
+    for row in std::iter::once(&header).chain(&rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect();
+
+        output.push_str(line.join("  ").trim_end());
+        output.push('\n');
+    }
+
+    output
+}
+
+/// Quote `field` if it contains anything CSV gives a special meaning
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_pads_columns_to_the_widest_cell() {
+        let output = table(
+            ["NAME", "STATE"],
+            [
+                [String::from("W1"), String::from("running")],
+                [String::from("Dryer 12"), String::from("idle")],
+            ]
+            .into_iter(),
+        );
+
+        assert_eq!(
+            output,
+            "NAME      STATE\nW1        running\nDryer 12  idle\n"
+        );
+    }
+
+    #[test]
+    fn csv_field_quotes_only_when_needed() {
+        assert_eq!(csv_field("W1"), "W1");
+        assert_eq!(csv_field("W1, left"), "\"W1, left\"");
+        assert_eq!(csv_field("the \"big\" one"), "\"the \"\"big\"\" one\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+    }
+}
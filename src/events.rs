@@ -0,0 +1,651 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use prometheus_client::{
+    encoding::EncodeLabelSet,
+    metrics::{counter::Counter, family::Family},
+    registry::Registry,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, warn};
+
+use crate::{
+    pay2wash::model::{MachineKind, MachineState, MachineStatus, NumberBool, RemainingTime, UserId},
+    shutdown::Shutdown,
+};
+
+/// Something that happened to a machine, derived from two consecutive scrapes
+#[derive(Debug, Clone, Serialize)]
+pub struct MachineEvent {
+    pub location: String,
+    pub name: String,
+    pub kind: MachineKind,
+    /// UNIX timestamp of the scrape the event was observed in
+    pub observed_at: i64,
+    #[serde(flatten)]
+    pub event: MachineEventKind,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum MachineEventKind {
+    /// The machine's state changed, sent once per change before the more specific events
+    StateChange {
+        previous: MachineState,
+        current: MachineState,
+    },
+    CycleStarted {
+        starter: UserId,
+        remaining_time: RemainingTime,
+        remaining_time_is_from_machine: NumberBool,
+    },
+    CycleFinished {
+        starter: UserId,
+        /// When the cycle was first observed, `None` if it was already running on startup
+        started_at: Option<i64>,
+    },
+    Reserved {
+        reserver: UserId,
+    },
+    ReservationReleased {
+        reserver: UserId,
+        reserved_at: Option<i64>,
+    },
+    EnteredMaintenance,
+    LeftMaintenance {
+        entered_at: Option<i64>,
+    },
+    /// The machine is free again, after whichever cycle, reservation or maintenance just ended
+    BecameIdle,
+    GatewayWentOffline,
+    GatewayCameBack {
+        went_offline_at: Option<i64>,
+    },
+}
+
+impl MachineEventKind {
+    pub fn event_type(&self) -> MachineEventType {
+        match self {
+            MachineEventKind::StateChange { .. } => MachineEventType::StateChange,
+            MachineEventKind::CycleStarted { .. } => MachineEventType::CycleStarted,
+            MachineEventKind::CycleFinished { .. } => MachineEventType::CycleFinished,
+            MachineEventKind::Reserved { .. } => MachineEventType::Reserved,
+            MachineEventKind::ReservationReleased { .. } => MachineEventType::ReservationReleased,
+            MachineEventKind::EnteredMaintenance => MachineEventType::EnteredMaintenance,
+            MachineEventKind::LeftMaintenance { .. } => MachineEventType::LeftMaintenance,
+            MachineEventKind::BecameIdle => MachineEventType::BecameIdle,
+            MachineEventKind::GatewayWentOffline => MachineEventType::GatewayWentOffline,
+            MachineEventKind::GatewayCameBack { .. } => MachineEventType::GatewayCameBack,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        self.event_type().as_str()
+    }
+}
+
+/// The variants of [`MachineEventKind`] without their data, eg. to filter events by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MachineEventType {
+    StateChange,
+    CycleStarted,
+    CycleFinished,
+    Reserved,
+    ReservationReleased,
+    EnteredMaintenance,
+    LeftMaintenance,
+    BecameIdle,
+    GatewayWentOffline,
+    GatewayCameBack,
+}
+
+impl MachineEventType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MachineEventType::StateChange => "state_change",
+            MachineEventType::CycleStarted => "cycle_started",
+            MachineEventType::CycleFinished => "cycle_finished",
+            MachineEventType::Reserved => "reserved",
+            MachineEventType::ReservationReleased => "reservation_released",
+            MachineEventType::EnteredMaintenance => "entered_maintenance",
+            MachineEventType::LeftMaintenance => "left_maintenance",
+            MachineEventType::BecameIdle => "became_idle",
+            MachineEventType::GatewayWentOffline => "gateway_went_offline",
+            MachineEventType::GatewayCameBack => "gateway_came_back",
+        }
+    }
+}
+
+/// Handle to the stream of [`MachineEvent`]s, cheap to clone for every subscriber
+#[derive(Debug, Clone)]
+pub struct Events(broadcast::Sender<MachineEvent>);
+
+impl Default for Events {
+    fn default() -> Self {
+        Self(broadcast::channel(256).0)
+    }
+}
+
+impl Events {
+    pub fn subscribe(&self) -> broadcast::Receiver<MachineEvent> {
+        self.0.subscribe()
+    }
+
+    fn send(&self, event: MachineEvent) {
+        debug!(?event, "machine event");
+
+        // Nobody listening is not an error
+        let _ = self.0.send(event);
+    }
+}
+
+/// What was last seen of a machine, along with when each part of it was first seen
+#[derive(Debug)]
+struct Observation {
+    state: MachineState,
+    state_since: Option<i64>,
+    gateway_offline: bool,
+    gateway_offline_since: Option<i64>,
+}
+
+/// Diffs consecutive scrapes of every machine and emits the resulting [`MachineEvent`]s
+///
+/// Clones share what was last seen of every machine, so accounts in the same building diff
+/// against each other's scrapes and every event is emitted once.
+#[derive(Debug, Clone)]
+pub struct EventEngine {
+    events: Events,
+    observations: Arc<Mutex<HashMap<(String, String), Observation>>>,
+}
+
+impl EventEngine {
+    pub fn new(events: Events) -> Self {
+        Self {
+            events,
+            observations: Arc::default(),
+        }
+    }
+
+    pub fn observe<'s>(
+        &self,
+        location: &str,
+        statuses: impl IntoIterator<Item = (&'s str, &'s MachineStatus)>,
+        observed_at: i64,
+    ) {
+        let mut observations = self
+            .observations
+            .lock()
+            .expect("observations should not be poisoned");
+
+        for (name, status) in statuses {
+            let gateway_offline = matches!(status.raw.gateway_offline, NumberBool::True);
+
+            let key = (location.to_owned(), name.to_owned());
+
+            let Some(previous) = observations.get(&key) else {
+                // Without history there is nothing to compare against, so no events either
+                observations.insert(
+                    key,
+                    Observation {
+                        state: status.state,
+                        state_since: None,
+                        gateway_offline,
+                        gateway_offline_since: None,
+                    },
+                );
+
+                continue;
+            };
+
+            let mut events = transitions(previous, &status.state);
+
+            match (previous.gateway_offline, gateway_offline) {
+                (false, true) => events.push(MachineEventKind::GatewayWentOffline),
+                (true, false) => events.push(MachineEventKind::GatewayCameBack {
+                    went_offline_at: previous.gateway_offline_since,
+                }),
+                _ => {}
+            }
+
+            let observation = Observation {
+                state: status.state,
+                state_since: if same_occupation(&previous.state, &status.state) {
+                    previous.state_since
+                } else {
+                    Some(observed_at)
+                },
+                gateway_offline,
+                gateway_offline_since: if previous.gateway_offline == gateway_offline {
+                    previous.gateway_offline_since
+                } else {
+                    Some(observed_at)
+                },
+            };
+
+            observations.insert(key, observation);
+
+            for event in events {
+                self.events.send(MachineEvent {
+                    location: location.to_owned(),
+                    name: name.to_owned(),
+                    kind: status.kind,
+                    observed_at,
+                    event,
+                });
+            }
+        }
+    }
+}
+
+/// If both states describe the same cycle, reservation or maintenance window
+fn same_occupation(previous: &MachineState, current: &MachineState) -> bool {
+    match (previous, current) {
+        (
+            MachineState::Running { starter: previous, .. },
+            MachineState::Running { starter: current, .. },
+        ) => previous == current,
+        (
+            MachineState::Reserved { reserver: previous },
+            MachineState::Reserved { reserver: current },
+        ) => previous == current,
+        (MachineState::Maintenance, MachineState::Maintenance)
+        | (MachineState::Idle, MachineState::Idle) => true,
+        _ => false,
+    }
+}
+
+fn transitions(previous: &Observation, current: &MachineState) -> Vec<MachineEventKind> {
+    let mut events = Vec::new();
+
+    if same_occupation(&previous.state, current) {
+        return events;
+    }
+
+    events.push(MachineEventKind::StateChange {
+        previous: previous.state,
+        current: *current,
+    });
+
+    // Whatever the machine was doing has ended
+    match previous.state {
+        MachineState::Running { starter, .. } => events.push(MachineEventKind::CycleFinished {
+            starter,
+            started_at: previous.state_since,
+        }),
+        MachineState::Reserved { reserver } => {
+            events.push(MachineEventKind::ReservationReleased {
+                reserver,
+                reserved_at: previous.state_since,
+            })
+        }
+        MachineState::Maintenance => events.push(MachineEventKind::LeftMaintenance {
+            entered_at: previous.state_since,
+        }),
+        MachineState::Idle => {}
+    }
+
+    // And something new has begun
+    match *current {
+        MachineState::Running {
+            starter,
+            remaining_time,
+            remaining_time_is_from_machine,
+        } => events.push(MachineEventKind::CycleStarted {
+            starter,
+            remaining_time,
+            remaining_time_is_from_machine,
+        }),
+        MachineState::Reserved { reserver } => {
+            events.push(MachineEventKind::Reserved { reserver })
+        }
+        MachineState::Maintenance => events.push(MachineEventKind::EnteredMaintenance),
+        MachineState::Idle => events.push(MachineEventKind::BecameIdle),
+    }
+
+    events
+}
+
+#[derive(Debug, Default)]
+pub struct EventMetrics {
+    events: Family<EventMetricKey, Counter>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+struct EventMetricKey {
+    location: String,
+    name: String,
+    kind: MachineKind,
+    event: &'static str,
+}
+
+impl EventMetrics {
+    pub fn register(&self, registry: &mut Registry) {
+        registry.register(
+            "events",
+            "the number of machine events observed, eg. cycles started or finished",
+            self.events.clone(),
+        );
+    }
+
+    /// Count every event until shutdown
+    pub async fn record(self, events: Events, mut shutdown: Shutdown) -> color_eyre::Result<()> {
+        let mut receiver = events.subscribe();
+
+        loop {
+            let event = tokio::select! {
+                event = receiver.recv() => event,
+                () = shutdown.wait() => return Ok(()),
+            };
+
+            match event {
+                Ok(event) => {
+                    self.events
+                        .get_or_create(&EventMetricKey {
+                            location: event.location,
+                            name: event.name,
+                            kind: event.kind,
+                            event: event.event.as_str(),
+                        })
+                        .inc();
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(skipped, "event metrics fell behind, some events were not counted");
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::sync::broadcast::Receiver;
+
+    use super::*;
+    use crate::pay2wash::model::JsonMachineStatus;
+
+    fn raw(running: bool, reserved: bool, in_maintenance: u8, user: u32) -> JsonMachineStatus {
+        serde_json::from_value(json!({
+            "running": running,
+            "starter": if running { user } else { 0 },
+            "reserved": reserved,
+            "reserver": if reserved { user } else { 0 },
+            "in_maintenance": in_maintenance,
+            "remaining_time": if running { "00:45" } else { "00:00" },
+            "gateway_offline": 0,
+            "remaining_time_is_from_machine": 1,
+            "controller_logic": 0,
+        }))
+        .expect("test status should deserialize")
+    }
+
+    fn status(raw: JsonMachineStatus) -> MachineStatus {
+        MachineStatus {
+            kind: MachineKind::Washer,
+            state: MachineState::try_from(&raw).expect("test status should be valid"),
+            raw,
+        }
+    }
+
+    fn idle() -> MachineStatus {
+        status(raw(false, false, 0, 0))
+    }
+
+    fn running(starter: u32) -> MachineStatus {
+        status(raw(true, false, 0, starter))
+    }
+
+    fn reserved(reserver: u32) -> MachineStatus {
+        status(raw(false, true, 0, reserver))
+    }
+
+    fn maintenance() -> MachineStatus {
+        status(raw(false, false, 1, 0))
+    }
+
+    fn gateway_offline(mut status: MachineStatus) -> MachineStatus {
+        status.raw.gateway_offline = NumberBool::True;
+        status
+    }
+
+    fn observe(engine: &EventEngine, status: &MachineStatus, observed_at: i64) {
+        engine.observe("17", [("W1", status)], observed_at);
+    }
+
+    fn received(receiver: &mut Receiver<MachineEvent>) -> Vec<MachineEventKind> {
+        std::iter::from_fn(|| receiver.try_recv().ok())
+            .map(|event| event.event)
+            .collect()
+    }
+
+    fn types(events: &[MachineEventKind]) -> Vec<&'static str> {
+        events.iter().map(MachineEventKind::as_str).collect()
+    }
+
+    fn engine() -> (EventEngine, Receiver<MachineEvent>) {
+        let events = Events::default();
+        let receiver = events.subscribe();
+
+        (EventEngine::new(events), receiver)
+    }
+
+    #[test]
+    fn first_observation_sends_nothing() {
+        let (engine, mut receiver) = engine();
+
+        observe(&engine, &gateway_offline(running(42)), 0);
+
+        assert!(received(&mut receiver).is_empty());
+    }
+
+    #[test]
+    fn unchanged_state_sends_nothing() {
+        let (engine, mut receiver) = engine();
+
+        observe(&engine, &running(42), 0);
+        observe(&engine, &running(42), 60);
+        observe(&engine, &idle(), 120);
+        observe(&engine, &idle(), 180);
+
+        let events = received(&mut receiver);
+        assert_eq!(
+            types(&events),
+            ["state_change", "cycle_finished", "became_idle"]
+        );
+    }
+
+    #[test]
+    fn cycle() {
+        let (engine, mut receiver) = engine();
+
+        observe(&engine, &idle(), 0);
+        observe(&engine, &running(42), 60);
+
+        let events = received(&mut receiver);
+        assert_eq!(types(&events), ["state_change", "cycle_started"]);
+        assert!(matches!(
+            events[0],
+            MachineEventKind::StateChange {
+                previous: MachineState::Idle,
+                current: MachineState::Running { .. },
+            }
+        ));
+        assert!(matches!(
+            events[1],
+            MachineEventKind::CycleStarted { starter, .. } if u32::from(starter) == 42
+        ));
+
+        observe(&engine, &idle(), 3000);
+
+        let events = received(&mut receiver);
+        assert_eq!(
+            types(&events),
+            ["state_change", "cycle_finished", "became_idle"]
+        );
+        assert!(matches!(
+            events[1],
+            MachineEventKind::CycleFinished {
+                starter,
+                started_at: Some(60),
+            } if u32::from(starter) == 42
+        ));
+    }
+
+    #[test]
+    fn cycle_running_on_startup_has_no_start() {
+        let (engine, mut receiver) = engine();
+
+        observe(&engine, &running(42), 0);
+        observe(&engine, &idle(), 60);
+
+        let events = received(&mut receiver);
+        assert!(matches!(
+            events[1],
+            MachineEventKind::CycleFinished {
+                started_at: None,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn new_starter_is_a_new_cycle() {
+        let (engine, mut receiver) = engine();
+
+        observe(&engine, &running(42), 0);
+        observe(&engine, &running(43), 60);
+
+        assert_eq!(
+            types(&received(&mut receiver)),
+            ["state_change", "cycle_finished", "cycle_started"]
+        );
+    }
+
+    #[test]
+    fn reservation() {
+        let (engine, mut receiver) = engine();
+
+        observe(&engine, &idle(), 0);
+        observe(&engine, &reserved(7), 60);
+        observe(&engine, &running(7), 120);
+
+        let events = received(&mut receiver);
+        assert_eq!(
+            types(&events),
+            [
+                "state_change",
+                "reserved",
+                "state_change",
+                "reservation_released",
+                "cycle_started"
+            ]
+        );
+        assert!(matches!(
+            events[3],
+            MachineEventKind::ReservationReleased {
+                reserver,
+                reserved_at: Some(60),
+            } if u32::from(reserver) == 7
+        ));
+    }
+
+    #[test]
+    fn maintenance_window() {
+        let (engine, mut receiver) = engine();
+
+        observe(&engine, &idle(), 0);
+        observe(&engine, &maintenance(), 60);
+        observe(&engine, &idle(), 120);
+
+        let events = received(&mut receiver);
+        assert_eq!(
+            types(&events),
+            [
+                "state_change",
+                "entered_maintenance",
+                "state_change",
+                "left_maintenance",
+                "became_idle"
+            ]
+        );
+        assert!(matches!(
+            events[3],
+            MachineEventKind::LeftMaintenance {
+                entered_at: Some(60)
+            }
+        ));
+    }
+
+    #[test]
+    fn gateway_flaps() {
+        let (engine, mut receiver) = engine();
+
+        observe(&engine, &idle(), 0);
+        observe(&engine, &gateway_offline(idle()), 60);
+        observe(&engine, &gateway_offline(idle()), 120);
+        observe(&engine, &idle(), 180);
+
+        let events = received(&mut receiver);
+        assert_eq!(
+            types(&events),
+            ["gateway_went_offline", "gateway_came_back"]
+        );
+        assert!(matches!(
+            events[1],
+            MachineEventKind::GatewayCameBack {
+                went_offline_at: Some(60)
+            }
+        ));
+    }
+
+    #[test]
+    fn gateway_offline_on_startup_has_no_start() {
+        let (engine, mut receiver) = engine();
+
+        observe(&engine, &gateway_offline(idle()), 0);
+        observe(&engine, &running(42), 60);
+
+        let events = received(&mut receiver);
+        assert_eq!(
+            types(&events),
+            ["state_change", "cycle_started", "gateway_came_back"]
+        );
+        assert!(matches!(
+            events[2],
+            MachineEventKind::GatewayCameBack {
+                went_offline_at: None
+            }
+        ));
+    }
+
+    #[test]
+    fn clones_share_observations() {
+        let (engine, mut receiver) = engine();
+        let other_account = engine.clone();
+
+        observe(&engine, &idle(), 0);
+        observe(&other_account, &running(42), 30);
+        observe(&engine, &running(42), 60);
+
+        assert_eq!(
+            types(&received(&mut receiver)),
+            ["state_change", "cycle_started"]
+        );
+    }
+
+    #[test]
+    fn machines_are_diffed_per_location() {
+        let (engine, mut receiver) = engine();
+
+        engine.observe("17", [("W1", &idle())], 0);
+        engine.observe("18", [("W1", &running(42))], 0);
+        engine.observe("17", [("W1", &idle())], 60);
+
+        assert!(received(&mut receiver).is_empty());
+    }
+}
@@ -0,0 +1,235 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use serde::Serialize;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+
+use crate::{
+    events::{Events, MachineEventKind},
+    pay2wash::model::{MachineKind, MachineState, NumberBool},
+    shutdown::Shutdown,
+    status::{MachineKey, MachineReport},
+};
+
+/// The number of most recent cycle lengths remembered per machine
+const LEARNED_CYCLES: usize = 32;
+
+/// Learns how long cycles take per machine to predict when machines will be free
+#[derive(Debug, Clone, Default)]
+pub struct Forecaster {
+    machines: Arc<Mutex<BTreeMap<MachineKey, MachineCycles>>>,
+}
+
+#[derive(Debug, Default)]
+struct MachineCycles {
+    /// When the running cycle was first observed, `None` if it was already running on startup
+    running_since: Option<i64>,
+    /// Lengths in seconds of the last [`LEARNED_CYCLES`] cycles seen from start to finish
+    lengths: VecDeque<i64>,
+}
+
+impl MachineCycles {
+    fn median_length(&self) -> Option<i64> {
+        let mut lengths: Vec<i64> = self.lengths.iter().copied().collect();
+        lengths.sort_unstable();
+
+        lengths.get(lengths.len() / 2).copied()
+    }
+}
+
+/// When at least `count` machines of `kind` are expected to be free
+#[derive(Debug, Serialize)]
+pub struct Forecast {
+    pub kind: MachineKind,
+    pub count: usize,
+    /// UNIX timestamp, `None` if fewer than `count` machines are expected to become free
+    pub free_at: Option<i64>,
+    pub wait_seconds: Option<i64>,
+    /// Rough probability between 0 and 1 that `count` machines will actually be free by `free_at`
+    pub confidence: f64,
+    /// Every machine of `kind`, earliest free first
+    pub machines: Vec<MachineForecast>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MachineForecast {
+    pub location: String,
+    pub name: String,
+    /// UNIX timestamp, `None` for reserved machines and machines in maintenance
+    pub free_at: Option<i64>,
+    pub confidence: f64,
+    pub basis: ForecastBasis,
+}
+
+/// What a machine's [`MachineForecast`] is based on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForecastBasis {
+    Idle,
+    /// The remaining time as reported by the machine itself
+    MachineRemainingTime,
+    /// The median length of the cycles previously seen on the machine
+    LearnedCycleLength,
+    /// The remaining time as estimated by pay2wash, when neither of the above are available
+    EstimatedRemainingTime,
+    /// Reserved or in maintenance, so there is no telling when it will be free
+    Unavailable,
+}
+
+impl Forecaster {
+    /// Learn cycle lengths from every event until shutdown
+    pub async fn learn(self, events: Events, mut shutdown: Shutdown) -> color_eyre::Result<()> {
+        let mut receiver = events.subscribe();
+
+        loop {
+            let event = tokio::select! {
+                event = receiver.recv() => event,
+                () = shutdown.wait() => return Ok(()),
+            };
+
+            let event = match event {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(skipped, "forecaster fell behind, some cycles were not learned");
+                    continue;
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            };
+
+            let mut machines = self.machines.lock().expect("forecaster should not be poisoned");
+
+            let machine = machines
+                .entry(MachineKey {
+                    location: event.location,
+                    name: event.name,
+                })
+                .or_default();
+
+            match event.event {
+                MachineEventKind::CycleStarted { .. } => {
+                    machine.running_since = Some(event.observed_at);
+                }
+                MachineEventKind::CycleFinished { started_at, .. } => {
+                    machine.running_since = None;
+
+                    if let Some(started_at) = started_at {
+                        if machine.lengths.len() == LEARNED_CYCLES {
+                            machine.lengths.pop_front();
+                        }
+
+                        machine.lengths.push_back(event.observed_at - started_at);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Predict when at least `count` machines of `kind` out of `reports` will be free
+    pub fn forecast<'r>(
+        &self,
+        reports: impl IntoIterator<Item = &'r MachineReport>,
+        kind: MachineKind,
+        count: usize,
+        now: i64,
+    ) -> Forecast {
+        let machines = self.machines.lock().expect("forecaster should not be poisoned");
+
+        let mut forecasts: Vec<MachineForecast> = reports
+            .into_iter()
+            .filter(|report| report.kind == kind)
+            .map(|report| {
+                let cycles = machines.get(&MachineKey {
+                    location: report.location.clone(),
+                    name: report.name.clone(),
+                });
+
+                let (free_at, confidence, basis) = match report.state {
+                    MachineState::Idle => (Some(now), 1.0, ForecastBasis::Idle),
+                    MachineState::Running {
+                        remaining_time,
+                        remaining_time_is_from_machine,
+                        ..
+                    } => {
+                        let remaining = i64::try_from(remaining_time.into_inner().as_secs())
+                            .expect("remaining time should not overflow an i64");
+
+                        let learned = cycles.and_then(|cycles| {
+                            Some(cycles.running_since? + cycles.median_length()?)
+                        });
+
+                        match (remaining_time_is_from_machine, learned) {
+                            (NumberBool::True, _) => (
+                                Some(report.updated_at + remaining),
+                                0.9,
+                                ForecastBasis::MachineRemainingTime,
+                            ),
+                            // pay2wash only guesses the remaining time when the machine does not
+                            // report it, what the machine usually takes is a better guess
+                            (_, Some(learned)) => {
+                                (Some(learned), 0.6, ForecastBasis::LearnedCycleLength)
+                            }
+                            (_, None) => (
+                                Some(report.updated_at + remaining),
+                                0.4,
+                                ForecastBasis::EstimatedRemainingTime,
+                            ),
+                        }
+                    }
+                    MachineState::Reserved { .. } | MachineState::Maintenance => {
+                        (None, 0.0, ForecastBasis::Unavailable)
+                    }
+                };
+
+                // A machine that should have finished already is taking longer than expected
+                let (free_at, confidence) = match free_at {
+                    Some(free_at) if free_at < now => (Some(now), confidence / 2.0),
+                    free_at => (free_at, confidence),
+                };
+
+                // Stale data from an offline gateway may no longer be accurate
+                let confidence = if matches!(report.gateway_offline, NumberBool::True) {
+                    confidence / 2.0
+                } else {
+                    confidence
+                };
+
+                MachineForecast {
+                    location: report.location.clone(),
+                    name: report.name.clone(),
+                    free_at,
+                    confidence,
+                    basis,
+                }
+            })
+            .collect();
+
+        // Unavailable machines go last
+        forecasts.sort_by_key(|forecast| (forecast.free_at.is_none(), forecast.free_at));
+
+        let earliest = forecasts.get(..count).filter(|earliest| {
+            count > 0 && earliest.iter().all(|forecast| forecast.free_at.is_some())
+        });
+
+        let (free_at, confidence) = match earliest {
+            Some(earliest) => (
+                earliest.iter().filter_map(|forecast| forecast.free_at).max(),
+                // Assuming the machines finish independently of each other
+                earliest.iter().map(|forecast| forecast.confidence).product(),
+            ),
+            None => (None, 0.0),
+        };
+
+        Forecast {
+            kind,
+            count,
+            free_at,
+            wait_seconds: free_at.map(|free_at| free_at - now),
+            confidence,
+            machines: forecasts,
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use color_eyre::eyre::{Context, ContextCompat};
+use rusqlite::{params, Connection};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tracing::{error, info, warn};
+
+use crate::{
+    events::{Events, MachineEvent, MachineEventKind},
+    shutdown::Shutdown,
+};
+
+/// Schema migrations in order, the database's `user_version` is the number of migrations applied
+const MIGRATIONS: &[&str] = &[
+    include_str!("../migrations/0001_initial.sql"),
+    include_str!("../migrations/0002_interrupted.sql"),
+];
+
+/// Persistent record of every cycle, reservation and maintenance window, backed by SQLite
+#[derive(Debug, Clone)]
+pub struct History {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl History {
+    /// Open or create the database at `path`, bringing its schema up to date
+    pub fn open(path: &Path) -> color_eyre::Result<Self> {
+        let mut connection = Connection::open(path)
+            .wrap_err_with(|| format!("failed to open history database {}", path.display()))?;
+
+        migrate(&mut connection).wrap_err("failed to migrate history database")?;
+
+        let interrupted = mark_interrupted(&connection)
+            .wrap_err("failed to mark interrupted rows in history database")?;
+
+        if interrupted > 0 {
+            info!(interrupted, "marked rows left open by the previous run as interrupted");
+        }
+
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Write every event to the database until shutdown, and those still queued at shutdown
+    pub async fn record(self, events: Events, mut shutdown: Shutdown) -> color_eyre::Result<()> {
+        let mut receiver = events.subscribe();
+
+        loop {
+            let event = tokio::select! {
+                event = receiver.recv() => event,
+                () = shutdown.wait() => break,
+            };
+
+            match event {
+                Ok(event) => self.insert(event).await,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(skipped, "history fell behind, some events were not recorded");
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            }
+        }
+
+        // The events of the last scrape may not have been received yet
+        loop {
+            match receiver.try_recv() {
+                Ok(event) => self.insert(event).await,
+                Err(TryRecvError::Lagged(skipped)) => {
+                    warn!(skipped, "history fell behind, some events were not recorded");
+                }
+                Err(TryRecvError::Empty | TryRecvError::Closed) => return Ok(()),
+            }
+        }
+    }
+
+    async fn insert(&self, event: MachineEvent) {
+        let connection = Arc::clone(&self.connection);
+
+        let result = tokio::task::spawn_blocking(move || {
+            let connection = connection
+                .lock()
+                .expect("history connection should not be poisoned");
+
+            insert_event(&connection, &event)
+        })
+        .await
+        .wrap_err("history writer panicked")
+        .and_then(|result| result);
+
+        // A full disk or locked database should not take the exporter down with it
+        if let Err(error) = result {
+            error!(?error, "failed to record event in history");
+        }
+    }
+}
+
+fn migrate(connection: &mut Connection) -> color_eyre::Result<()> {
+    let applied: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+    let pending = MIGRATIONS
+        .get(applied..)
+        .wrap_err_with(|| format!("database is at version {applied}, which is newer than this build"))?;
+
+    for (index, migration) in pending.iter().enumerate() {
+        let version = applied + index + 1;
+
+        let transaction = connection.transaction()?;
+        transaction
+            .execute_batch(migration)
+            .wrap_err_with(|| format!("migration {version} failed"))?;
+        transaction.pragma_update(None, "user_version", version)?;
+        transaction.commit()?;
+
+        info!(version, "applied history database migration");
+    }
+
+    Ok(())
+}
+
+/// Mark every row that is still open as interrupted, returning how many were marked
+///
+/// Their end happened while the scraper was not running, and as the first scrape after a restart
+/// only sets the baseline of the event engine it will never be recorded.
+fn mark_interrupted(connection: &Connection) -> color_eyre::Result<usize> {
+    let mut marked = 0;
+
+    for statement in [
+        "UPDATE cycles SET interrupted = 1 WHERE ended_at IS NULL AND interrupted = 0",
+        "UPDATE reservations SET interrupted = 1 WHERE released_at IS NULL AND interrupted = 0",
+        "UPDATE maintenance_windows SET interrupted = 1 WHERE left_at IS NULL AND interrupted = 0",
+    ] {
+        marked += connection.execute(statement, [])?;
+    }
+
+    Ok(marked)
+}
+
+fn insert_event(connection: &Connection, event: &MachineEvent) -> color_eyre::Result<()> {
+    let MachineEvent {
+        location,
+        name,
+        kind,
+        observed_at,
+        ..
+    } = event;
+    let kind = kind.as_str();
+
+    match event.event {
+        MachineEventKind::CycleStarted {
+            starter,
+            remaining_time,
+            remaining_time_is_from_machine,
+        } => {
+            connection.execute(
+                "INSERT INTO cycles (location, machine, kind, started_at, starter, initial_remaining_time, remaining_time_is_from_machine)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    location,
+                    name,
+                    kind,
+                    observed_at,
+                    u32::from(starter),
+                    remaining_time.into_inner().as_secs(),
+                    u8::from(remaining_time_is_from_machine),
+                ],
+            )?;
+        }
+        MachineEventKind::CycleFinished {
+            starter,
+            started_at,
+        } => {
+            let closed = connection.execute(
+                "UPDATE cycles SET ended_at = ?1 WHERE id = (
+                    SELECT id FROM cycles
+                    WHERE location = ?2 AND machine = ?3 AND starter = ?4 AND ended_at IS NULL
+                      AND interrupted = 0
+                    ORDER BY id DESC LIMIT 1
+                 )",
+                params![observed_at, location, name, u32::from(starter)],
+            )?;
+
+            // The cycle was already running when the scraper started, so there is no open row
+            if closed == 0 {
+                connection.execute(
+                    "INSERT INTO cycles (location, machine, kind, started_at, ended_at, starter)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![location, name, kind, started_at, observed_at, u32::from(starter)],
+                )?;
+            }
+        }
+        MachineEventKind::Reserved { reserver } => {
+            connection.execute(
+                "INSERT INTO reservations (location, machine, kind, reserved_at, reserver)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![location, name, kind, observed_at, u32::from(reserver)],
+            )?;
+        }
+        MachineEventKind::ReservationReleased {
+            reserver,
+            reserved_at,
+        } => {
+            let closed = connection.execute(
+                "UPDATE reservations SET released_at = ?1 WHERE id = (
+                    SELECT id FROM reservations
+                    WHERE location = ?2 AND machine = ?3 AND reserver = ?4 AND released_at IS NULL
+                      AND interrupted = 0
+                    ORDER BY id DESC LIMIT 1
+                 )",
+                params![observed_at, location, name, u32::from(reserver)],
+            )?;
+
+            if closed == 0 {
+                connection.execute(
+                    "INSERT INTO reservations (location, machine, kind, reserved_at, released_at, reserver)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![location, name, kind, reserved_at, observed_at, u32::from(reserver)],
+                )?;
+            }
+        }
+        MachineEventKind::EnteredMaintenance => {
+            connection.execute(
+                "INSERT INTO maintenance_windows (location, machine, kind, entered_at)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![location, name, kind, observed_at],
+            )?;
+        }
+        MachineEventKind::LeftMaintenance { entered_at } => {
+            let closed = connection.execute(
+                "UPDATE maintenance_windows SET left_at = ?1 WHERE id = (
+                    SELECT id FROM maintenance_windows
+                    WHERE location = ?2 AND machine = ?3 AND left_at IS NULL
+                      AND interrupted = 0
+                    ORDER BY id DESC LIMIT 1
+                 )",
+                params![observed_at, location, name],
+            )?;
+
+            if closed == 0 {
+                connection.execute(
+                    "INSERT INTO maintenance_windows (location, machine, kind, entered_at, left_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![location, name, kind, entered_at, observed_at],
+                )?;
+            }
+        }
+        // Recorded through the more specific events sent along with it
+        MachineEventKind::StateChange { .. } => {}
+        // Already recorded by whichever cycle, reservation or maintenance window just ended
+        MachineEventKind::BecameIdle => {}
+        // Gateway outages say nothing about how the machines are used
+        MachineEventKind::GatewayWentOffline | MachineEventKind::GatewayCameBack { .. } => {}
+    }
+
+    Ok(())
+}
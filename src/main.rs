@@ -3,39 +3,102 @@
 
 use std::{
     borrow::Cow,
-    convert::Infallible,
+    collections::{HashMap, HashSet},
+    fmt::{self, Write},
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
     str::FromStr,
-    sync::atomic::AtomicI64,
-    time::{Duration, SystemTime},
+    sync::{atomic::AtomicI64, Arc, Mutex},
+    time::Duration,
 };
 
-use color_eyre::eyre::{bail, eyre, Context};
-use metrics::boolean::{BooleanGauge, NumberBooleanGauge};
-use pay2wash::{AuthenticatedSession, AuthenticatedSessionError};
+use api::ApiState;
+use clap::{Parser, Subcommand};
+use commands::OutputFormat;
+use color_eyre::eyre::Context;
+use config::{Config, ReplayConfig};
+use events::{EventEngine, EventMetrics, Events};
+use forecast::Forecaster;
+use futures_util::future::try_join_all;
+use history::History;
+use metrics::{
+    boolean::{BooleanGauge, NumberBooleanGauge},
+    stale::StaleFamily,
+};
+use occupancy::Occupancy;
 use prometheus_client::{
-    encoding::EncodeLabelSet,
+    encoding::{EncodeLabelSet, EncodeLabelValue, LabelValueEncoder},
     metrics::{family::Family, gauge::Gauge},
+    registry::Registry,
 };
+use scrape::{Pipeline, ScrapeMetrics};
 use sentry::{types::Dsn, SessionMode};
-use serde::Deserialize;
-use strict_types::{Email, Password};
-use tokio::time::{interval, MissedTickBehavior};
-use tracing::{info, warn, Level, trace, debug};
+use session_cache::SessionCache;
+use shutdown::Shutdown;
+use status::StatusBoard;
+use tracing::{info, warn, Level};
 use tracing_error::ErrorLayer;
-use tracing_subscriber::{prelude::*, util::SubscriberInitExt, EnvFilter};
-
-use crate::pay2wash::Pay2WashClient;
-
+use tracing_subscriber::{fmt::MakeWriter, prelude::*, util::SubscriberInitExt, EnvFilter};
+
+use crate::pay2wash::model::{MachineKind, MachineState};
+
+mod api;
+mod archive;
+mod backoff;
+mod commands;
+mod config;
+mod events;
+mod forecast;
+mod history;
 mod metrics;
+mod mqtt;
+mod notify;
+mod occupancy;
 mod pay2wash;
+mod replay;
+mod scrape;
+mod session_cache;
+mod shutdown;
+mod status;
 mod strict_types;
+mod time;
+mod webhooks;
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    /// TOML config file, environment variables override the values in it
+    #[arg(long, short, env = "PAIN2WASH_CONFIG", global = true)]
+    config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-#[derive(Debug, Deserialize)]
-struct Environment {
-    pay2wash_email: Email,
-    pay2wash_password: Password,
-
-    sentry_dsn: Option<String>,
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Scrape pay2wash and serve the results, the default when no command is given
+    Serve,
+    /// Log in once and print the status of every machine
+    Status {
+        #[arg(long, short, value_enum, default_value_t)]
+        format: OutputFormat,
+    },
+    /// Log in once and print the location and machines pay2wash shows the account
+    LoginCheck,
+    /// Replay archived snapshots through the same decoding as a scrape and serve the results
+    Replay {
+        /// Directory the snapshots were archived in, see `ARCHIVE_DIRECTORY`
+        directory: PathBuf,
+        /// How many times faster than real time to replay, 0 replays as fast as possible
+        #[arg(long, default_value_t = 1)]
+        speed: u32,
+        /// Days of snapshots the occupancy statistics are calculated over, overrides
+        /// `occupancy.lookback_days`
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        occupancy_lookback_days: Option<u32>,
+    },
 }
 
 fn main() -> color_eyre::Result<()> {
@@ -44,17 +107,49 @@ fn main() -> color_eyre::Result<()> {
 
     color_eyre::install()?;
 
-    let environment: Environment = envy::from_env()
-        .map_err(|err| match err {
-            envy::Error::MissingValue(key) => eyre!("missing environment variable {key}"),
-            envy::Error::Custom(message) => eyre!(message),
-        })
-        .wrap_err("failed to load environment")?;
+    let cli = Cli::parse();
+
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(cli.config.as_deref()),
+        Command::Status { format } => {
+            let config = Config::load(cli.config.as_deref())?;
+
+            init_tracing(io::stderr)?;
+
+            runtime().block_on(commands::status(config.pay2wash.into_accounts(None)?, format))
+        }
+        Command::LoginCheck => {
+            let config = Config::load(cli.config.as_deref())?;
+
+            init_tracing(io::stderr)?;
+
+            runtime().block_on(commands::login_check(config.pay2wash.into_accounts(None)?))
+        }
+        Command::Replay {
+            directory,
+            speed,
+            occupancy_lookback_days,
+        } => {
+            let mut config = ReplayConfig::load(cli.config.as_deref())?;
+
+            if let Some(lookback_days) = occupancy_lookback_days {
+                config.occupancy.lookback_days = lookback_days;
+            }
+
+            init_tracing(io::stdout)?;
+
+            runtime().block_on(replay_main(config, directory, speed))
+        }
+    }
+}
+
+fn serve(config: Option<&Path>) -> color_eyre::Result<()> {
+    let config = Config::load(config)?;
 
     // TODO: Sentry
-    let _sentry = sentry::init(sentry::ClientOptions {
+    let sentry = sentry::init(sentry::ClientOptions {
         attach_stacktrace: true,
-        dsn: environment
+        dsn: config
             .sentry_dsn
             .as_deref()
             .map(Dsn::from_str)
@@ -68,8 +163,29 @@ fn main() -> color_eyre::Result<()> {
         ..Default::default()
     });
 
+    init_tracing(io::stdout)?;
+
+    if config.sentry_dsn.is_none() {
+        warn!("no sentry dsn provided, error reporting disabled");
+    }
+
+    let result = runtime().block_on(async_main(config));
+
+    // Deliver any outstanding events before the process exits
+    if !sentry.close(Some(Duration::from_secs(2))) {
+        warn!("failed to flush all sentry events before shutting down");
+    }
+
+    result
+}
+
+/// Log to `writer`, which is stderr for commands that print their results to stdout
+fn init_tracing<W>(writer: W) -> color_eyre::Result<()>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
     tracing_subscriber::Registry::default()
-        .with(tracing_subscriber::fmt::layer().pretty())
+        .with(tracing_subscriber::fmt::layer().pretty().with_writer(writer))
         .with(
             EnvFilter::builder()
                 .with_default_directive(Level::INFO.into())
@@ -79,114 +195,420 @@ fn main() -> color_eyre::Result<()> {
         .with(ErrorLayer::default())
         .init();
 
-    if environment.sentry_dsn.is_none() {
-        warn!("no sentry dsn provided, error reporting disabled");
-    }
+    Ok(())
+}
 
+fn runtime() -> tokio::runtime::Runtime {
     // Since fly.io is a one core machine, we only need the current thread
     tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()
         .expect("failed to build tokio runtime")
-        .block_on(async_main(environment))
 }
 
-async fn async_main(environment: Environment) -> color_eyre::Result<()> {
-    info!(?environment);
+async fn async_main(config: Config) -> color_eyre::Result<()> {
+    info!(?config);
+
+    let metrics = Metrics::default();
+
+    let mut registry = Registry::default();
+
+    metrics.register(&mut registry);
+
+    let (archive, archive_writer) = config
+        .archive
+        .directory
+        .map(|directory| {
+            archive::archive(
+                directory,
+                config.archive.max_file_bytes,
+                config.archive.max_files,
+            )
+        })
+        .transpose()?
+        .unzip();
+
+    let accounts = config.pay2wash.into_accounts(archive)?;
+
+    let session_cache = config
+        .session_cache
+        .file
+        .zip(config.session_cache.key.as_ref())
+        .map(|(file, key)| SessionCache::open(file, key))
+        .transpose()?;
+
+    let scrape_metrics = ScrapeMetrics::default();
+    scrape_metrics.register(registry.sub_registry_with_prefix(env!("CARGO_PKG_NAME")));
+
+    let event_metrics = EventMetrics::default();
+    event_metrics.register(registry.sub_registry_with_prefix(env!("CARGO_PKG_NAME")));
+
+    let history = config
+        .history
+        .database
+        .as_deref()
+        .map(History::open)
+        .transpose()?;
+
+    let webhooks = config
+        .webhooks
+        .file
+        .as_deref()
+        .map(webhooks::load)
+        .transpose()?
+        .unwrap_or_default();
+
+    let (notifier, notification_delivery) = config
+        .notifications
+        .file
+        .as_deref()
+        .map(notify::load)
+        .transpose()?
+        .map(notify::notifier)
+        .unzip();
+
+    let mqtt_publisher = config
+        .mqtt
+        .url
+        .as_ref()
+        .map(|url| mqtt::MqttPublisher::new(url, config.mqtt.prefix, config.mqtt.discovery_prefix))
+        .transpose()?;
+
+    let status_board = StatusBoard::default();
+    let events = Events::default();
+    let event_engine = EventEngine::new(events.clone());
+    let occupancy = Occupancy::new(config.occupancy.lookback_days);
+    let forecaster = Forecaster::default();
+
+    let shutdown = Shutdown::listen();
+
+    // Every account scrapes on its own, so one failing leaves the others up to date
+    let scrapers = accounts.into_iter().map(|account| {
+        scrape::scraper(
+            account,
+            config.scrape.interval,
+            scrape_metrics.clone(),
+            session_cache.clone(),
+            Pipeline {
+                metrics: metrics.clone(),
+                status_board: status_board.clone(),
+                event_engine: event_engine.clone(),
+                occupancy: occupancy.clone(),
+                notifier: notifier.clone(),
+                stale_after: config.scrape.stale_after,
+            },
+            shutdown.clone(),
+        )
+    });
+
+    let record_history = {
+        let (events, shutdown) = (events.clone(), shutdown.clone());
+
+        async move {
+            match history {
+                Some(history) => history.record(events, shutdown).await,
+                None => Ok(()),
+            }
+        }
+    };
+
+    let write_archive = {
+        let shutdown = shutdown.clone();
+
+        async move {
+            match archive_writer {
+                Some(archive_writer) => archive_writer.run(shutdown).await,
+                None => Ok(()),
+            }
+        }
+    };
+
+    let deliver_notifications = {
+        let shutdown = shutdown.clone();
+
+        async move {
+            match notification_delivery {
+                Some(notification_delivery) => notification_delivery.run(shutdown).await,
+                None => Ok(()),
+            }
+        }
+    };
 
+    let publish_mqtt = {
+        let (status_board, shutdown) = (status_board.clone(), shutdown.clone());
+
+        async move {
+            match mqtt_publisher {
+                Some(mqtt_publisher) => mqtt_publisher.run(status_board, shutdown).await,
+                None => Ok(()),
+            }
+        }
+    };
+
+    tokio::try_join!(
+        metrics::metrics_server(
+            registry,
+            ApiState {
+                status_board: status_board.clone(),
+                events: events.clone(),
+                occupancy: occupancy.clone(),
+                forecaster: forecaster.clone(),
+                shutdown: shutdown.clone(),
+            },
+            SocketAddr::new(config.server.address, config.server.port),
+            shutdown.clone()
+        ),
+        event_metrics.record(events.clone(), shutdown.clone()),
+        forecaster.learn(events.clone(), shutdown.clone()),
+        record_history,
+        write_archive,
+        webhooks::deliver(webhooks, events.clone(), shutdown.clone()),
+        deliver_notifications,
+        publish_mqtt,
+        try_join_all(scrapers)
+    )?;
+
+    info!("shut down gracefully");
+
+    Ok(())
+}
+
+async fn replay_main(
+    config: ReplayConfig,
+    directory: PathBuf,
+    speed: u32,
+) -> color_eyre::Result<()> {
     let metrics = Metrics::default();
 
-    let mut registry = prometheus_client::registry::Registry::with_prefix("machine");
-
-    registry.register(
-        "updated",
-        "the UNIX timestamp of when the provided machine_* data was updated per location",
-        metrics.updated.clone(),
-    );
-
-    registry.register(
-        "user_token",
-        "the user id whose data is being scraped per location",
-        metrics.user_token.clone(),
-    );
-
-    registry.register(
-        "running",
-        "boolean representing the running status of a specific machine",
-        metrics.running.clone(),
-    );
-
-    registry.register(
-        "remaining_time",
-        "time remaining on the running program in seconds",
-        metrics.remaining_time.clone(),
-    );
-
-    registry.register(
-        "starter",
-        "user id who started this machine",
-        metrics.starter.clone(),
-    );
-
-    registry.register(
-        "reserved",
-        "boolean representing if the machine is reserved",
-        metrics.reserved.clone(),
-    );
-
-    registry.register(
-        "reserver",
-        "user id who reserved this machine",
-        metrics.reserver.clone(),
-    );
-
-    registry.register(
-        "in_maintenance",
-        "boolean representing if the machine is under maintenance",
-        metrics.in_maintenance.clone(),
-    );
-
-    registry.register(
-        "gateway_offline",
-        "boolean representing if the machine's gateway is offline",
-        metrics.gateway_offline.clone(),
-    );
-
-    registry.register(
-        "remaining_time_is_from_machine",
-        "boolean representing if the machine's remaining_time is provided from the machine itself",
-        metrics.remaining_time_is_from_machine.clone(),
-    );
-
-    registry.register(
-        "controller_logic",
-        "unsure",
-        metrics.controller_logic.clone(),
-    );
-
-    let client = Pay2WashClient::new(environment.pay2wash_email, environment.pay2wash_password);
-
-    tokio::try_join!(metrics::metrics_server(registry), scraper(client, metrics))?;
+    let mut registry = Registry::default();
+
+    metrics.register(&mut registry);
+
+    let event_metrics = EventMetrics::default();
+    event_metrics.register(registry.sub_registry_with_prefix(env!("CARGO_PKG_NAME")));
+
+    let status_board = StatusBoard::default();
+    let events = Events::default();
+    let occupancy = Occupancy::new(config.occupancy.lookback_days);
+    let forecaster = Forecaster::default();
+
+    let shutdown = Shutdown::listen();
+
+    tokio::try_join!(
+        metrics::metrics_server(
+            registry,
+            ApiState {
+                status_board: status_board.clone(),
+                events: events.clone(),
+                occupancy: occupancy.clone(),
+                forecaster: forecaster.clone(),
+                shutdown: shutdown.clone(),
+            },
+            SocketAddr::new(config.server.address, config.server.port),
+            shutdown.clone()
+        ),
+        event_metrics.record(events.clone(), shutdown.clone()),
+        forecaster.learn(events.clone(), shutdown.clone()),
+        replay::replay(
+            directory,
+            speed,
+            // Replayed cycles are long done, nobody needs to be notified about them
+            Pipeline {
+                metrics,
+                status_board,
+                event_engine: EventEngine::new(events),
+                occupancy,
+                notifier: None,
+                stale_after: config.scrape.stale_after,
+            },
+            shutdown
+        )
+    )?;
+
+    info!("shut down gracefully");
 
     Ok(())
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 struct Metrics {
     updated: Family<LocationMetricKey, Gauge<i64, AtomicI64>>,
-    user_token: Family<LocationMetricKey, Gauge<i64, AtomicI64>>,
+    user_token: Family<UserTokenMetricKey, Gauge<i64, AtomicI64>>,
+
+    running: StaleFamily<WashingMachineMetricKey, BooleanGauge>,
+    starter: StaleFamily<WashingMachineMetricKey, Gauge<i64, AtomicI64>>,
+    remaining_time: StaleFamily<WashingMachineMetricKey, Gauge<i64, AtomicI64>>,
+
+    reserved: StaleFamily<WashingMachineMetricKey, BooleanGauge>,
+    reserver: StaleFamily<WashingMachineMetricKey, Gauge<i64, AtomicI64>>,
+
+    in_maintenance: StaleFamily<WashingMachineMetricKey, NumberBooleanGauge>,
+    gateway_offline: StaleFamily<WashingMachineMetricKey, NumberBooleanGauge>,
+    remaining_time_is_from_machine: StaleFamily<WashingMachineMetricKey, NumberBooleanGauge>,
+    controller_logic: StaleFamily<WashingMachineMetricKey, Gauge<i64, AtomicI64>>,
+
+    state: StaleFamily<MachineStateMetricKey, BooleanGauge>,
+
+    /// Number of scrapes per location, to tell which machines were missing from the last ones
+    generations: Arc<Mutex<HashMap<String, u64>>>,
+    /// Names of the accounts scraping each location, which is only removed once none are left
+    scrapers: Arc<Mutex<HashMap<String, HashSet<String>>>>,
+}
+
+impl Metrics {
+    /// Start the next scrape of `location`, returning its generation
+    fn next_generation(&self, location: &str) -> u64 {
+        let mut generations = self
+            .generations
+            .lock()
+            .expect("generations should not be poisoned");
+
+        let generation = generations.entry(location.to_owned()).or_default();
+        *generation += 1;
 
-    running: Family<WashingMachineMetricKey, BooleanGauge>,
-    starter: Family<WashingMachineMetricKey, Gauge<i64, AtomicI64>>,
-    remaining_time: Family<WashingMachineMetricKey, Gauge<i64, AtomicI64>>,
+        *generation
+    }
+
+    /// Remove the series of machines at `location` that were not seen for `stale_after` scrapes
+    /// as of `generation`, returning how many were removed
+    fn remove_stale(&self, location: &str, generation: u64, stale_after: u64) -> usize {
+        let machine = |key: &WashingMachineMetricKey| key.location == location;
+        let state = |key: &MachineStateMetricKey| key.location == location;
+
+        self.running.remove_stale(generation, stale_after, machine)
+            + self.starter.remove_stale(generation, stale_after, machine)
+            + self.remaining_time.remove_stale(generation, stale_after, machine)
+            + self.reserved.remove_stale(generation, stale_after, machine)
+            + self.reserver.remove_stale(generation, stale_after, machine)
+            + self.in_maintenance.remove_stale(generation, stale_after, machine)
+            + self.gateway_offline.remove_stale(generation, stale_after, machine)
+            + self.remaining_time_is_from_machine.remove_stale(generation, stale_after, machine)
+            + self.controller_logic.remove_stale(generation, stale_after, machine)
+            + self.state.remove_stale(generation, stale_after, state)
+    }
+
+    /// Record that `account` scrapes `location` instead of `previous`, returning whether no
+    /// account is left scraping `previous`
+    fn move_account(&self, account: &str, previous: Option<&str>, location: &str) -> bool {
+        let mut scrapers = self
+            .scrapers
+            .lock()
+            .expect("scrapers should not be poisoned");
+
+        scrapers
+            .entry(location.to_owned())
+            .or_default()
+            .insert(account.to_owned());
+
+        let Some(previous) = previous else {
+            return false;
+        };
+
+        let Some(accounts) = scrapers.get_mut(previous) else {
+            return true;
+        };
+
+        accounts.remove(account);
+
+        if accounts.is_empty() {
+            scrapers.remove(previous);
+
+            return true;
+        }
+
+        false
+    }
+
+    /// Remove every series of `location`, once it is no longer scraped at all
+    fn remove_location(&self, location: &str) {
+        self.updated.remove(&LocationMetricKey {
+            location: location.to_owned(),
+        });
+
+        self.generations
+            .lock()
+            .expect("generations should not be poisoned")
+            .remove(location);
+
+        // Nothing is newer than a max age of 0
+        self.remove_stale(location, 0, 0);
+    }
+
+    fn register(&self, registry: &mut Registry) {
+        let machine_registry = registry.sub_registry_with_prefix("machine");
+
+        machine_registry.register(
+            "updated",
+            "the UNIX timestamp of when the provided machine_* data was updated per location",
+            self.updated.clone(),
+        );
+
+        machine_registry.register(
+            "user_token",
+            "the user id whose data is being scraped per location and account",
+            self.user_token.clone(),
+        );
+
+        machine_registry.register(
+            "running",
+            "boolean representing the running status of a specific machine",
+            self.running.clone(),
+        );
+
+        machine_registry.register(
+            "remaining_time",
+            "time remaining on the running program in seconds",
+            self.remaining_time.clone(),
+        );
+
+        machine_registry.register(
+            "starter",
+            "user id who started this machine",
+            self.starter.clone(),
+        );
+
+        machine_registry.register(
+            "reserved",
+            "boolean representing if the machine is reserved",
+            self.reserved.clone(),
+        );
+
+        machine_registry.register(
+            "reserver",
+            "user id who reserved this machine",
+            self.reserver.clone(),
+        );
+
+        machine_registry.register(
+            "in_maintenance",
+            "boolean representing if the machine is under maintenance",
+            self.in_maintenance.clone(),
+        );
+
+        machine_registry.register(
+            "gateway_offline",
+            "boolean representing if the machine's gateway is offline",
+            self.gateway_offline.clone(),
+        );
 
-    reserved: Family<WashingMachineMetricKey, BooleanGauge>,
-    reserver: Family<WashingMachineMetricKey, Gauge<i64, AtomicI64>>,
+        machine_registry.register(
+            "remaining_time_is_from_machine",
+            "boolean representing if the machine's remaining_time is provided from the machine itself",
+            self.remaining_time_is_from_machine.clone(),
+        );
 
-    in_maintenance: Family<WashingMachineMetricKey, NumberBooleanGauge>,
-    gateway_offline: Family<WashingMachineMetricKey, NumberBooleanGauge>,
-    remaining_time_is_from_machine: Family<WashingMachineMetricKey, NumberBooleanGauge>,
-    controller_logic: Family<WashingMachineMetricKey, Gauge<i64, AtomicI64>>,
+        machine_registry.register(
+            "controller_logic",
+            "unsure",
+            self.controller_logic.clone(),
+        );
+
+        machine_registry.register(
+            "state",
+            "state set of the machine's decoded state, exactly one state is 1 per machine",
+            self.state.clone(),
+        );
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
@@ -194,113 +616,99 @@ pub struct LocationMetricKey {
     pub location: String,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+pub struct UserTokenMetricKey {
+    pub location: String,
+    /// Name of the scraped account, several can share a location
+    pub account: String,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
 pub struct WashingMachineMetricKey {
     pub location: String,
     pub name: String,
+    pub kind: MachineKind,
 }
 
-async fn scraper(client: Pay2WashClient, metrics: Metrics) -> color_eyre::Result<Infallible> {
-    let mut session: Option<AuthenticatedSession> = None;
-
-    let mut interval = interval(Duration::from_secs(60));
-    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+pub struct MachineStateMetricKey {
+    pub location: String,
+    pub name: String,
+    pub kind: MachineKind,
+    pub state: MachineStateLabel,
+}
 
-    loop {
-        interval.tick().await;
+impl EncodeLabelValue for MachineKind {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> Result<(), fmt::Error> {
+        encoder.write_str(self.as_str())
+    }
+}
 
-        let authenticated_session = if let Some(authenticated_session) = session.as_ref() {
-            authenticated_session
-        } else {
-            let authenticated_session = client
-                .authenticate()
-                .await
-                .wrap_err("failed to authenticate")?;
+/// The variants of [`MachineState`] without their data, in the style of an OpenMetrics StateSet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MachineStateLabel {
+    Running,
+    Reserved,
+    Maintenance,
+    Idle,
+}
 
-            &*session.insert(authenticated_session)
-        };
+impl MachineStateLabel {
+    pub const ALL: [MachineStateLabel; 4] = [
+        MachineStateLabel::Running,
+        MachineStateLabel::Reserved,
+        MachineStateLabel::Maintenance,
+        MachineStateLabel::Idle,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MachineStateLabel::Running => "running",
+            MachineStateLabel::Reserved => "reserved",
+            MachineStateLabel::Maintenance => "maintenance",
+            MachineStateLabel::Idle => "idle",
+        }
+    }
+}
 
-        let statuses = match client.get_machine_statuses(authenticated_session).await {
-            Ok(statuses) => statuses,
-            Err(AuthenticatedSessionError::BadSession) => {
-                warn!("authentication session was bad");
+impl From<&MachineState> for MachineStateLabel {
+    fn from(state: &MachineState) -> Self {
+        match state {
+            MachineState::Running { .. } => MachineStateLabel::Running,
+            MachineState::Reserved { .. } => MachineStateLabel::Reserved,
+            MachineState::Maintenance => MachineStateLabel::Maintenance,
+            MachineState::Idle => MachineStateLabel::Idle,
+        }
+    }
+}
 
-                session.take();
+impl EncodeLabelValue for MachineStateLabel {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> Result<(), fmt::Error> {
+        encoder.write_str(self.as_str())
+    }
+}
 
-                continue;
-            }
-            Err(AuthenticatedSessionError::Other(error)) => {
-                bail!(error);
-            }
-        };
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let location_key = LocationMetricKey {
-            location: authenticated_session.location.clone(),
-        };
+    #[test]
+    fn keeps_location_while_another_account_scrapes_it() {
+        let metrics = Metrics::default();
 
-        metrics.updated.get_or_create(&location_key).set(
-            SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .expect("time should only move forwards")
-                .as_secs()
-                .try_into()
-                .expect("unix timestamp should not overflow an i64"),
-        );
+        assert!(!metrics.move_account("0", None, "17"));
+        assert!(!metrics.move_account("1", None, "17"));
 
-        metrics
-            .user_token
-            .get_or_create(&location_key)
-            .set(i64::from(u32::from(authenticated_session.user_token)));
-
-        for (name, status) in statuses {
-            let metric_key = WashingMachineMetricKey {
-                location: authenticated_session.location.clone(),
-                name: String::from(name),
-            };
-
-            macro_rules! metric {
-                ($name:ident) => {
-                    metrics
-                        .$name
-                        .get_or_create(&metric_key)
-                        .set(status.raw.$name)
-                };
-                ($name:ident as i64) => {
-                    metrics
-                        .$name
-                        .get_or_create(&metric_key)
-                        .set(i64::from(status.raw.$name))
-                };
-                ($name:ident as u32 => i64) => {
-                    metrics
-                        .$name
-                        .get_or_create(&metric_key)
-                        .set(i64::from(u32::from(status.raw.$name)))
-                };
-            }
+        assert!(!metrics.move_account("0", Some("17"), "18"));
+        assert!(metrics.move_account("1", Some("17"), "18"));
+    }
 
-            metric!(running);
-            metric!(starter as u32 => i64);
-
-            metrics.remaining_time.get_or_create(&metric_key).set(
-                status
-                    .raw
-                    .remaining_time
-                    .into_inner()
-                    .as_secs()
-                    .try_into()
-                    .expect("remaining time should not overflow an i64"),
-            );
-
-            metric!(reserved);
-            metric!(reserver as u32 => i64);
-
-            metric!(in_maintenance);
-            metric!(gateway_offline);
-            metric!(remaining_time_is_from_machine);
-            metric!(controller_logic as i64);
-        }
+    #[test]
+    fn removes_location_of_a_single_account() {
+        let metrics = Metrics::default();
 
-        debug!(period = ?interval.period(), "waiting for next update");
+        assert!(!metrics.move_account("0", None, "17"));
+        assert!(metrics.move_account("0", Some("17"), "18"));
+        assert!(metrics.move_account("0", Some("18"), "17"));
     }
 }
@@ -1,7 +1,4 @@
-use std::{
-    net::{Ipv4Addr, SocketAddrV4},
-    sync::Arc,
-};
+use std::{net::SocketAddr, sync::Arc};
 
 use axum::{extract::State, response::Redirect, routing::get, Router, Server};
 use color_eyre::{eyre::Context, Report};
@@ -11,12 +8,24 @@ use sentry_tower::{SentryHttpLayer, SentryLayer};
 use tower_http::{catch_panic::CatchPanicLayer, trace::TraceLayer};
 use tracing::{error, info};
 
+use crate::{
+    api::{self, ApiState},
+    shutdown::Shutdown,
+};
+
 pub mod boolean;
 pub mod gauge_info;
-
-pub async fn metrics_server(registry: Registry) -> Result<(), Report> {
+pub mod stale;
+
+pub async fn metrics_server(
+    registry: Registry,
+    api_state: ApiState,
+    listen: SocketAddr,
+    mut shutdown: Shutdown,
+) -> Result<(), Report> {
     let router = Router::new()
         .route("/metrics", get(metrics).with_state(Arc::new(registry)))
+        .nest("/api/v1", api::router(api_state))
         .fallback(|| async { Redirect::to("/metrics") })
         .layer(
             tower::ServiceBuilder::new()
@@ -26,11 +35,11 @@ pub async fn metrics_server(registry: Registry) -> Result<(), Report> {
                 .layer(CatchPanicLayer::new()),
         );
 
-    info!("Starting metrics server on http://localhost:9091");
+    info!("Starting metrics server on http://{listen}");
 
-    let listen = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 9091);
-    Server::bind(&listen.into())
+    Server::bind(&listen)
         .serve(router.into_make_service())
+        .with_graceful_shutdown(async move { shutdown.wait().await })
         .await
         .wrap_err("axum server ran into a problem")
 }
@@ -0,0 +1,158 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    ops::Deref,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use prometheus_client::{
+    encoding::{EncodeLabelSet, EncodeMetric, MetricEncoder},
+    metrics::{family::Family, MetricType, TypedMetric},
+};
+
+/// A [`Family`] that remembers the generation each series was last set in, so series that stop
+/// being set can be removed instead of exporting their last value forever
+#[derive(Debug, Clone)]
+pub struct StaleFamily<S, M> {
+    family: Family<S, M>,
+    last_seen: Arc<Mutex<HashMap<S, u64>>>,
+}
+
+impl<S, M> Default for StaleFamily<S, M>
+where
+    S: Clone + Hash + Eq,
+    M: Default,
+{
+    fn default() -> Self {
+        Self {
+            family: Family::default(),
+            last_seen: Arc::default(),
+        }
+    }
+}
+
+impl<S, M> StaleFamily<S, M>
+where
+    S: Clone + Hash + Eq,
+{
+    /// The series of `label_set`, creating it if needed and marking it as seen in `generation`
+    pub fn get_or_create(&self, label_set: &S, generation: u64) -> impl Deref<Target = M> + '_ {
+        self.last_seen().insert(label_set.clone(), generation);
+
+        self.family.get_or_create(label_set)
+    }
+
+    /// Remove every series matching `in_scope` that has not been seen for `max_age` generations
+    /// as of `generation`, returning how many were removed
+    pub fn remove_stale(
+        &self,
+        generation: u64,
+        max_age: u64,
+        in_scope: impl Fn(&S) -> bool,
+    ) -> usize {
+        let mut removed = 0;
+
+        self.last_seen().retain(|label_set, &mut last_seen| {
+            if !in_scope(label_set) || generation.saturating_sub(last_seen) < max_age {
+                return true;
+            }
+
+            self.family.remove(label_set);
+            removed += 1;
+
+            false
+        });
+
+        removed
+    }
+
+    fn last_seen(&self) -> MutexGuard<'_, HashMap<S, u64>> {
+        self.last_seen
+            .lock()
+            .expect("stale family should not be poisoned")
+    }
+}
+
+impl<S, M: TypedMetric> TypedMetric for StaleFamily<S, M> {
+    const TYPE: MetricType = <M as TypedMetric>::TYPE;
+}
+
+impl<S, M> EncodeMetric for StaleFamily<S, M>
+where
+    S: Clone + Hash + Eq + EncodeLabelSet,
+    M: EncodeMetric + TypedMetric,
+{
+    fn encode(&self, encoder: MetricEncoder) -> Result<(), std::fmt::Error> {
+        self.family.encode(encoder)
+    }
+
+    fn metric_type(&self) -> MetricType {
+        M::TYPE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus_client::{encoding::text::encode, metrics::gauge::Gauge, registry::Registry};
+
+    use super::*;
+
+    type Labels = Vec<(&'static str, &'static str)>;
+
+    fn labels(location: &'static str, name: &'static str) -> Labels {
+        vec![("location", location), ("name", name)]
+    }
+
+    fn exported(family: &StaleFamily<Labels, Gauge>) -> String {
+        let mut registry = Registry::default();
+        registry.register("machine", "test", family.clone());
+
+        let mut exported = String::new();
+        encode(&mut exported, &registry).expect("encoding should not fail");
+
+        exported
+    }
+
+    #[test]
+    fn removes_series_not_seen_for_max_age() {
+        let family = StaleFamily::<Labels, Gauge>::default();
+
+        family.get_or_create(&labels("17", "W1"), 1).set(1);
+        family.get_or_create(&labels("17", "W2"), 1).set(1);
+        family.get_or_create(&labels("17", "W1"), 3).set(1);
+
+        assert_eq!(family.remove_stale(3, 3, |_| true), 0);
+        assert_eq!(family.remove_stale(4, 3, |_| true), 1);
+
+        let exported = exported(&family);
+        assert!(exported.contains("name=\"W1\""));
+        assert!(!exported.contains("name=\"W2\""));
+    }
+
+    #[test]
+    fn keeps_series_out_of_scope() {
+        let family = StaleFamily::<Labels, Gauge>::default();
+
+        family.get_or_create(&labels("17", "W1"), 1).set(1);
+        family.get_or_create(&labels("18", "W1"), 1).set(1);
+
+        let removed = family.remove_stale(10, 3, |labels| labels.contains(&("location", "17")));
+
+        assert_eq!(removed, 1);
+        let exported = exported(&family);
+        assert!(!exported.contains("location=\"17\""));
+        assert!(exported.contains("location=\"18\""));
+    }
+
+    #[test]
+    fn removed_series_come_back_when_set_again() {
+        let family = StaleFamily::<Labels, Gauge>::default();
+
+        family.get_or_create(&labels("17", "W1"), 1).set(1);
+        family.remove_stale(10, 3, |_| true);
+        family.get_or_create(&labels("17", "W1"), 11).set(1);
+
+        assert_eq!(family.remove_stale(11, 3, |_| true), 0);
+        assert!(exported(&family).contains("name=\"W1\""));
+    }
+}
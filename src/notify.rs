@@ -0,0 +1,535 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use color_eyre::eyre::{bail, eyre, Context};
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    time::sleep,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    backoff::Backoff,
+    pay2wash::{
+        model::{MachineKind, MachineState, MachineStatus, UserId},
+        AuthenticatedSession,
+    },
+    shutdown::Shutdown,
+    status::MachineKey,
+    strict_types::Secret,
+    webhooks,
+};
+
+/// Attempts made to send a notification over a channel before giving up on it
+const MAX_ATTEMPTS: u32 = 4;
+/// No cycle takes anywhere near a day, so larger values are mistakes
+const MAX_REMIND_BEFORE_MINUTES: u64 = 24 * 60;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NotificationsFile {
+    #[serde(default)]
+    person: Vec<Person>,
+}
+
+/// Someone to notify about the cycles they start
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Person {
+    pub name: String,
+    /// Cycles started by the account scraping the machine's location belong to this person
+    #[serde(default)]
+    pub scraping_account: bool,
+    #[serde(default)]
+    pub user_ids: Vec<UserId>,
+    /// How long before the remaining time runs out the first notification is sent
+    #[serde(default = "default_remind_before_minutes")]
+    pub remind_before_minutes: u64,
+    #[serde(rename = "channel")]
+    pub channels: Vec<Channel>,
+}
+
+fn default_remind_before_minutes() -> u64 {
+    5
+}
+
+impl Person {
+    fn started(&self, starter: UserId, session: &AuthenticatedSession) -> bool {
+        self.user_ids.contains(&starter) || (self.scraping_account && starter == session.user_token)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Channel {
+    /// A JSON POST signed like the event webhooks
+    Webhook { url: Url, secret: Secret },
+    /// A plain text POST in the style of ntfy.sh
+    Ntfy { url: Url, token: Option<Secret> },
+    Smtp {
+        host: String,
+        port: Option<u16>,
+        #[serde(default)]
+        tls: SmtpTls,
+        username: Option<String>,
+        password: Option<Secret>,
+        from: String,
+        to: String,
+    },
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpTls {
+    /// Upgrade the connection with STARTTLS, usually on port 587
+    #[default]
+    Starttls,
+    /// Connect over TLS from the start, usually on port 465
+    Wrapper,
+    /// Plain text, only meant for local testing
+    None,
+}
+
+/// Read the `[[person]]` tables of the TOML file at `path`
+pub fn load(path: &Path) -> color_eyre::Result<Vec<Person>> {
+    let contents = fs::read_to_string(path)
+        .wrap_err_with(|| format!("failed to read notifications file {}", path.display()))?;
+
+    let file: NotificationsFile = toml::from_str(&contents)
+        .wrap_err_with(|| format!("invalid notifications file {}", path.display()))?;
+
+    for (person_index, person) in file.person.iter().enumerate() {
+        if person.remind_before_minutes > MAX_REMIND_BEFORE_MINUTES {
+            bail!(
+                "person[{person_index}].remind_before_minutes must be at most {MAX_REMIND_BEFORE_MINUTES}"
+            );
+        }
+
+        for (channel_index, channel) in person.channels.iter().enumerate() {
+            if let Channel::Smtp { from, to, .. } = channel {
+                for (key, address) in [("from", from), ("to", to)] {
+                    address.parse::<Mailbox>().wrap_err_with(|| {
+                        format!("person[{person_index}].channel[{channel_index}].{key} is not a valid address")
+                    })?;
+                }
+            }
+        }
+    }
+
+    Ok(file.person)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    /// The remaining time dropped below the person's `remind_before_minutes`
+    AlmostDone,
+    /// The cycle has ended
+    Done,
+}
+
+impl NotificationKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotificationKind::AlmostDone => "almost_done",
+            NotificationKind::Done => "done",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Notification {
+    #[serde(skip)]
+    person: Arc<Person>,
+    #[serde(rename = "person")]
+    person_name: String,
+    pub location: String,
+    pub name: String,
+    pub kind: MachineKind,
+    pub notification: NotificationKind,
+    /// Seconds left on the cycle, only for [`NotificationKind::AlmostDone`]
+    pub remaining_time: Option<u64>,
+    /// UNIX timestamp of the scrape the notification was triggered by
+    pub observed_at: i64,
+}
+
+impl Notification {
+    fn title(&self) -> String {
+        match self.notification {
+            NotificationKind::AlmostDone => format!("{} is almost done", self.name),
+            NotificationKind::Done => format!("{} is done", self.name),
+        }
+    }
+
+    fn message(&self) -> String {
+        match (self.notification, self.remaining_time) {
+            (NotificationKind::AlmostDone, Some(remaining_time)) => format!(
+                "Your laundry in {} at location {} is done in about {} minutes.",
+                self.name,
+                self.location,
+                remaining_time.div_ceil(60),
+            ),
+            _ => format!(
+                "Your laundry in {} at location {} is done.",
+                self.name, self.location
+            ),
+        }
+    }
+}
+
+/// A cycle started by someone who wants to be notified about it
+#[derive(Debug, Clone)]
+struct TrackedCycle {
+    person: Arc<Person>,
+    starter: UserId,
+    reminded: bool,
+}
+
+/// Spots the cycles of every [`Person`] in each scrape and queues their notifications for the
+/// [`NotificationDelivery`], clones queue onto the same delivery
+#[derive(Debug, Clone)]
+pub struct Notifier {
+    people: Vec<Arc<Person>>,
+    /// Shared by every clone, so accounts in the same building notify about each cycle once
+    cycles: Arc<Mutex<BTreeMap<MachineKey, TrackedCycle>>>,
+    queue: mpsc::Sender<Notification>,
+}
+
+/// Sends queued notifications over every channel of the person they are for
+#[derive(Debug)]
+pub struct NotificationDelivery {
+    receiver: mpsc::Receiver<Notification>,
+}
+
+pub fn notifier(people: Vec<Person>) -> (Notifier, NotificationDelivery) {
+    let (queue, receiver) = mpsc::channel(64);
+
+    (
+        Notifier {
+            people: people.into_iter().map(Arc::new).collect(),
+            cycles: Arc::default(),
+            queue,
+        },
+        NotificationDelivery { receiver },
+    )
+}
+
+impl Notifier {
+    pub fn observe(
+        &self,
+        session: &AuthenticatedSession,
+        statuses: &HashMap<String, MachineStatus>,
+        observed_at: i64,
+    ) {
+        let mut cycles = self.cycles.lock().expect("cycles should not be poisoned");
+
+        for (name, status) in statuses {
+            let key = MachineKey {
+                location: session.location.clone(),
+                name: name.clone(),
+            };
+
+            let MachineState::Running {
+                starter,
+                remaining_time,
+                ..
+            } = status.state
+            else {
+                if let Some(cycle) = cycles.remove(&key) {
+                    self.send(
+                        cycle.person,
+                        &key,
+                        status.kind,
+                        NotificationKind::Done,
+                        None,
+                        observed_at,
+                    );
+                }
+
+                continue;
+            };
+
+            // Someone else started the machine in between two scrapes
+            if let Some(cycle) = cycles.get(&key).filter(|cycle| cycle.starter != starter) {
+                let person = Arc::clone(&cycle.person);
+                cycles.remove(&key);
+
+                self.send(
+                    person,
+                    &key,
+                    status.kind,
+                    NotificationKind::Done,
+                    None,
+                    observed_at,
+                );
+            }
+
+            if !cycles.contains_key(&key) {
+                let Some(person) = self
+                    .people
+                    .iter()
+                    .find(|person| person.started(starter, session))
+                else {
+                    continue;
+                };
+
+                debug!(person = person.name, machine = name, "tracking cycle");
+
+                cycles.insert(
+                    key.clone(),
+                    TrackedCycle {
+                        person: Arc::clone(person),
+                        starter,
+                        reminded: false,
+                    },
+                );
+            }
+
+            let remaining_time = remaining_time.into_inner();
+
+            let Some(cycle) = cycles.get_mut(&key) else {
+                continue;
+            };
+
+            if !cycle.reminded
+                && remaining_time <= Duration::from_secs(cycle.person.remind_before_minutes * 60)
+            {
+                cycle.reminded = true;
+
+                let person = Arc::clone(&cycle.person);
+
+                self.send(
+                    person,
+                    &key,
+                    status.kind,
+                    NotificationKind::AlmostDone,
+                    Some(remaining_time.as_secs()),
+                    observed_at,
+                );
+            }
+        }
+
+        // Machines that were removed or renamed will never finish their cycle
+        cycles.retain(|key, _| {
+            key.location != session.location || statuses.contains_key(&key.name)
+        });
+    }
+
+    fn send(
+        &self,
+        person: Arc<Person>,
+        key: &MachineKey,
+        kind: MachineKind,
+        notification: NotificationKind,
+        remaining_time: Option<u64>,
+        observed_at: i64,
+    ) {
+        info!(
+            person = person.name,
+            machine = key.name,
+            notification = notification.as_str(),
+            "notifying"
+        );
+
+        let notification = Notification {
+            person_name: person.name.clone(),
+            person,
+            location: key.location.clone(),
+            name: key.name.clone(),
+            kind,
+            notification,
+            remaining_time,
+            observed_at,
+        };
+
+        if let Err(TrySendError::Full(notification)) = self.queue.try_send(notification) {
+            warn!(
+                person = notification.person_name,
+                "notification queue is full, dropping notification"
+            );
+        }
+    }
+}
+
+impl NotificationDelivery {
+    /// Send every queued notification until shutdown
+    pub async fn run(mut self, shutdown: Shutdown) -> color_eyre::Result<()> {
+        let http_client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .wrap_err("failed to build notification http client")?;
+
+        let mut stop = shutdown.clone();
+
+        loop {
+            let notification = tokio::select! {
+                notification = self.receiver.recv() => notification,
+                () = stop.wait() => return Ok(()),
+            };
+
+            let Some(notification) = notification else {
+                return Ok(());
+            };
+
+            let notification = Arc::new(notification);
+
+            // Retrying a failing channel should not delay any other notification or channel
+            for channel in 0..notification.person.channels.len() {
+                tokio::spawn(deliver(
+                    http_client.clone(),
+                    Arc::clone(&notification),
+                    channel,
+                    shutdown.clone(),
+                ));
+            }
+        }
+    }
+}
+
+/// Send `notification` over the person's channel at index `channel`
+async fn deliver(
+    http_client: reqwest::Client,
+    notification: Arc<Notification>,
+    channel: usize,
+    mut shutdown: Shutdown,
+) {
+    let Some(channel) = notification.person.channels.get(channel) else {
+        return;
+    };
+
+    let mut backoff = Backoff::new(Duration::from_secs(5), Duration::from_secs(60));
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        match send(&http_client, channel, &notification).await {
+            Ok(()) => {
+                debug!(
+                    person = notification.person_name,
+                    attempt, "sent notification"
+                );
+                return;
+            }
+            Err(error) if attempt == MAX_ATTEMPTS => {
+                warn!(
+                    ?error,
+                    person = notification.person_name,
+                    attempt,
+                    "giving up on notification"
+                );
+            }
+            Err(error) => {
+                let delay = backoff.next_delay();
+
+                warn!(
+                    ?error,
+                    person = notification.person_name,
+                    attempt,
+                    ?delay,
+                    "notification failed, retrying"
+                );
+
+                tokio::select! {
+                    () = sleep(delay) => {},
+                    () = shutdown.wait() => return,
+                }
+            }
+        }
+    }
+}
+
+async fn send(
+    http_client: &reqwest::Client,
+    channel: &Channel,
+    notification: &Notification,
+) -> color_eyre::Result<()> {
+    match channel {
+        Channel::Webhook { url, secret } => {
+            webhooks::post_signed(
+                http_client,
+                url,
+                secret,
+                notification.notification.as_str(),
+                serde_json::to_string(notification).wrap_err("failed to serialize notification")?,
+            )
+            .await
+        }
+        Channel::Ntfy { url, token } => {
+            let request = http_client
+                .post(url.clone())
+                .header("Title", notification.title())
+                .header("Tags", "basket")
+                .body(notification.message());
+
+            let request = match token {
+                Some(token) => request.bearer_auth(token.expose()),
+                None => request,
+            };
+
+            request
+                .send()
+                .await
+                .wrap_err("failed to POST ntfy notification")?
+                .error_for_status()
+                .wrap_err("ntfy responded with non-success status code")?;
+
+            Ok(())
+        }
+        Channel::Smtp {
+            host,
+            port,
+            tls,
+            username,
+            password,
+            from,
+            to,
+        } => {
+            let transport = match tls {
+                SmtpTls::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host),
+                SmtpTls::Wrapper => AsyncSmtpTransport::<Tokio1Executor>::relay(host),
+                SmtpTls::None => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(
+                    host,
+                )),
+            }
+            .wrap_err_with(|| format!("failed to set up smtp relay {host}"))?;
+
+            let transport = match port {
+                Some(port) => transport.port(*port),
+                None => transport,
+            };
+
+            let transport = match (username, password) {
+                (Some(username), Some(password)) => transport.credentials(Credentials::new(
+                    username.clone(),
+                    password.expose().to_owned(),
+                )),
+                _ => transport,
+            };
+
+            let message = Message::builder()
+                .from(from.parse().wrap_err("invalid from address")?)
+                .to(to.parse().wrap_err("invalid to address")?)
+                .subject(notification.title())
+                .body(notification.message())
+                .map_err(|error| eyre!(error))
+                .wrap_err("failed to build email")?;
+
+            transport
+                .build()
+                .send(message)
+                .await
+                .wrap_err("failed to send email")?;
+
+            Ok(())
+        }
+    }
+}
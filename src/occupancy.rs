@@ -0,0 +1,277 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use serde::Serialize;
+
+use crate::pay2wash::model::{MachineKind, MachineState, MachineStatus};
+
+/// Scrapes are counted per quarter hour, so every UTC offset in use maps onto whole local hours
+pub const MINUTES_PER_QUARTER: i64 = 15;
+const SECONDS_PER_QUARTER: i64 = MINUTES_PER_QUARTER * 60;
+const QUARTERS_PER_HOUR: i64 = 4;
+const HOURS_PER_WEEK: i64 = 7 * 24;
+/// The UNIX epoch fell on a Thursday, 3 days after the Monday each week is counted from
+const EPOCH_HOUR_OF_WEEK: i64 = 3 * 24;
+const WEEK_LEN: usize = 7 * 24;
+
+/// Rolling statistics of how busy the machines are per quarter hour, fed by every scrape
+#[derive(Debug, Clone)]
+pub struct Occupancy {
+    lookback_hours: i64,
+    quarters: Arc<Mutex<BTreeMap<QuarterKey, Counts>>>,
+}
+
+/// UNIX quarter hour, location and machine name prefix the counts are kept per
+type QuarterKey = (i64, String, char);
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Counts {
+    busy: u32,
+    samples: u32,
+}
+
+impl Counts {
+    fn add(&mut self, other: Counts) {
+        self.busy = self.busy.saturating_add(other.busy);
+        self.samples = self.samples.saturating_add(other.samples);
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PrefixOccupancy {
+    pub location: String,
+    pub prefix: char,
+    pub kind: MachineKind,
+    /// Every hour of the week, starting at Monday 00:00
+    pub hours: Vec<HourOccupancy>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HourOccupancy {
+    pub hour_of_week: i64,
+    /// Fraction of machine scrapes that found a machine running or reserved, `None` without any
+    pub busy_fraction: Option<f64>,
+    pub samples: u32,
+}
+
+impl Occupancy {
+    pub fn new(lookback_days: u32) -> Self {
+        Self {
+            lookback_hours: i64::from(lookback_days) * 24,
+            quarters: Arc::default(),
+        }
+    }
+
+    pub fn lookback_hours(&self) -> i64 {
+        self.lookback_hours
+    }
+
+    /// Count the state of every machine in a scrape of `location` at `observed_at`
+    pub fn observe<'s>(
+        &self,
+        location: &str,
+        statuses: impl IntoIterator<Item = (&'s str, &'s MachineStatus)>,
+        observed_at: i64,
+    ) {
+        let quarter = observed_at.div_euclid(SECONDS_PER_QUARTER);
+
+        let mut quarters = self.quarters.lock().expect("occupancy should not be poisoned");
+
+        for (name, status) in statuses {
+            // Uppercased like `MachineKind::from_name`, so prefix and kind always agree
+            let Some(prefix) = name.trim().chars().next() else { continue };
+            let prefix = prefix.to_ascii_uppercase();
+
+            let busy = matches!(
+                status.state,
+                MachineState::Running { .. } | MachineState::Reserved { .. }
+            );
+
+            quarters
+                .entry((quarter, location.to_owned(), prefix))
+                .or_default()
+                .add(Counts {
+                    busy: u32::from(busy),
+                    samples: 1,
+                });
+        }
+
+        // Time is taken from the scrapes rather than the clock so replayed scrapes roll over too
+        let oldest = quarter - self.lookback_hours * QUARTERS_PER_HOUR;
+        quarters.retain(|&(quarter, _, _), _| quarter > oldest);
+    }
+
+    /// The occupancy of every location and machine name prefix per hour of the week, with hours
+    /// shifted by `utc_offset` minutes from UTC, only of `location` if set
+    ///
+    /// `utc_offset` is rounded down to a multiple of [`MINUTES_PER_QUARTER`].
+    pub fn summary(&self, utc_offset: i64, location: Option<&str>) -> Vec<PrefixOccupancy> {
+        let quarters = self.quarters.lock().expect("occupancy should not be poisoned");
+
+        let offset = utc_offset.div_euclid(MINUTES_PER_QUARTER);
+
+        let mut prefixes: BTreeMap<(&str, char), Vec<Counts>> = BTreeMap::new();
+
+        for ((quarter, quarter_location, prefix), &counts) in quarters.iter() {
+            if location.is_some_and(|location| location != quarter_location) {
+                continue;
+            }
+
+            let hour = (quarter + offset).div_euclid(QUARTERS_PER_HOUR);
+            let hour_of_week = (hour + EPOCH_HOUR_OF_WEEK).rem_euclid(HOURS_PER_WEEK);
+
+            let hour_of_week =
+                usize::try_from(hour_of_week).expect("hour of week should not be negative");
+
+            prefixes
+                .entry((quarter_location, *prefix))
+                .or_insert_with(|| vec![Counts::default(); WEEK_LEN])[hour_of_week]
+                .add(counts);
+        }
+
+        prefixes
+            .into_iter()
+            .map(|((location, prefix), week)| PrefixOccupancy {
+                location: location.to_owned(),
+                prefix,
+                kind: MachineKind::from_name(&prefix.to_string()),
+                hours: (0..)
+                    .zip(week)
+                    .map(|(hour_of_week, counts)| HourOccupancy {
+                        hour_of_week,
+                        busy_fraction: (counts.samples > 0)
+                            .then(|| f64::from(counts.busy) / f64::from(counts.samples)),
+                        samples: counts.samples,
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::pay2wash::model::JsonMachineStatus;
+
+    /// Monday 2024-01-01 00:00 UTC
+    const MONDAY: i64 = 1_704_067_200;
+
+    fn status(running: bool) -> MachineStatus {
+        let raw: JsonMachineStatus = serde_json::from_value(json!({
+            "running": running,
+            "starter": 0,
+            "reserved": false,
+            "reserver": 0,
+            "in_maintenance": 0,
+            "remaining_time": "00:00",
+            "gateway_offline": 0,
+            "remaining_time_is_from_machine": 0,
+            "controller_logic": 0,
+        }))
+        .expect("test status should deserialize");
+
+        MachineStatus {
+            kind: MachineKind::Washer,
+            state: MachineState::try_from(&raw).expect("test status should be valid"),
+            raw,
+        }
+    }
+
+    /// Hours of the week that have samples, with their busy fraction
+    fn sampled_hours(summary: &PrefixOccupancy) -> Vec<(i64, f64)> {
+        summary
+            .hours
+            .iter()
+            .filter_map(|hour| Some((hour.hour_of_week, hour.busy_fraction?)))
+            .collect()
+    }
+
+    #[test]
+    fn counts_busy_fraction_per_hour_of_week() {
+        let occupancy = Occupancy::new(28);
+
+        occupancy.observe(
+            "17",
+            [("W1", &status(true)), ("W2", &status(false))],
+            MONDAY + 600,
+        );
+        occupancy.observe(
+            "17",
+            [("W1", &status(true)), ("W2", &status(true))],
+            MONDAY + 1200,
+        );
+        occupancy.observe("17", [("D1", &status(false))], MONDAY + 3 * 60 * 60);
+
+        let summary = occupancy.summary(0, None);
+
+        assert_eq!(summary.len(), 2);
+        assert_eq!(
+            (summary[0].prefix, summary[0].kind),
+            ('D', MachineKind::Dryer)
+        );
+        assert_eq!(sampled_hours(&summary[0]), [(3, 0.0)]);
+        assert_eq!(
+            (summary[1].prefix, summary[1].kind),
+            ('W', MachineKind::Washer)
+        );
+        assert_eq!(sampled_hours(&summary[1]), [(0, 0.75)]);
+        assert_eq!(summary[1].hours[0].samples, 4);
+    }
+
+    #[test]
+    fn weeks_start_on_monday() {
+        let occupancy = Occupancy::new(28);
+
+        // The UNIX epoch was a Thursday
+        occupancy.observe("17", [("W1", &status(true))], 0);
+
+        assert_eq!(sampled_hours(&occupancy.summary(0, None)[0]), [(72, 1.0)]);
+    }
+
+    #[test]
+    fn shifts_hours_by_utc_offset_in_minutes() {
+        let occupancy = Occupancy::new(28);
+
+        occupancy.observe("17", [("W1", &status(true))], MONDAY + 40 * 60);
+
+        let hour = |utc_offset| sampled_hours(&occupancy.summary(utc_offset, None)[0])[0].0;
+
+        assert_eq!(hour(0), 0);
+        assert_eq!(hour(120), 2);
+        // 05:10 and 06:25 local
+        assert_eq!(hour(270), 5);
+        assert_eq!(hour(345), 6);
+        // Monday 00:10 and Sunday 23:55 local
+        assert_eq!(hour(-30), 0);
+        assert_eq!(hour(-45), 167);
+    }
+
+    #[test]
+    fn filters_by_location() {
+        let occupancy = Occupancy::new(28);
+
+        occupancy.observe("17", [("W1", &status(true))], MONDAY);
+        occupancy.observe("18", [("W1", &status(false))], MONDAY);
+
+        let summary = occupancy.summary(0, Some("18"));
+
+        assert_eq!(summary.len(), 1);
+        assert_eq!(summary[0].location, "18");
+        assert_eq!(sampled_hours(&summary[0]), [(0, 0.0)]);
+    }
+
+    #[test]
+    fn forgets_scrapes_older_than_lookback() {
+        let occupancy = Occupancy::new(1);
+
+        occupancy.observe("17", [("W1", &status(true))], MONDAY);
+        occupancy.observe("17", [("W1", &status(false))], MONDAY + 24 * 60 * 60);
+
+        assert_eq!(sampled_hours(&occupancy.summary(0, None)[0]), [(24, 0.0)]);
+    }
+}
@@ -3,65 +3,215 @@ use color_eyre::{
     Help, SectionExt,
 };
 use once_cell::sync::Lazy;
-use reqwest::redirect;
+use reqwest::{
+    cookie::{CookieStore, Jar},
+    redirect, StatusCode, Url,
+};
 use scraper::{ElementRef, Html, Selector};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tracing::{info, trace};
+use tracing::{info, trace, warn};
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::{self, Debug},
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
-use crate::strict_types::{Email, Password, PasswordRef};
+use crate::{
+    archive::{Archive, Snapshot, SnapshotKind},
+    strict_types::{Email, Password, PasswordRef},
+    time::unix_timestamp,
+};
 
-use self::model::{JsonMachineStatus, MachineState, MachineStatus, UserId};
+use self::model::{JsonMachineStatus, Machine, MachineState, MachineStatus, UserId};
 
 pub mod model;
 
 pub struct Pay2WashClient {
+    endpoints: Pay2WashEndpoints,
     email: Email,
     password: Password,
     http_client: reqwest::Client,
+    /// Cookie store of `http_client`, kept around to store and restore sessions
+    cookies: Arc<Jar>,
+    /// Time after which the machine mappings of a session are fetched again
+    mappings_refresh_interval: Duration,
+    archive: Option<Archive>,
 }
 
 impl Debug for Pay2WashClient {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Pay2WashClient")
+            .field("endpoints", &self.endpoints)
             .field("email", &self.email)
             .field("password", &self.password)
             .finish_non_exhaustive()
     }
 }
 
-#[derive(Debug, Error)]
-pub enum AuthenticatedSessionError {
+#[derive(Error)]
+pub enum Pay2WashError {
     #[error("session is no longer authenticated")]
     BadSession,
+    /// The server did not accept the login credentials
+    #[error(transparent)]
+    Authentication(color_eyre::Report),
+    /// The request failed to complete or the server responded with an error status code
+    #[error(transparent)]
+    Http(color_eyre::Report),
+    #[error(transparent)]
+    Json(color_eyre::Report),
     #[error(transparent)]
-    Other(#[from] color_eyre::Report),
+    HtmlExtraction(color_eyre::Report),
+    /// The machine statuses returned by the server do not describe a valid [`MachineState`]
+    #[error(transparent)]
+    StateInvariant(color_eyre::Report),
+}
+
+/// Broad classification of a [`Pay2WashError`] used to decide how to recover from it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// Network problems, timeouts and server errors that will likely resolve themselves
+    Transient,
+    /// The login was rejected, retrying too eagerly risks locking the account
+    Credential,
+    /// The server's html or json changed shape and the scraper needs to be updated
+    Schema,
+}
+
+impl Debug for Pay2WashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Forward to the report so the error chain and sections are preserved in logs
+        match self {
+            Pay2WashError::BadSession => write!(f, "BadSession"),
+            Pay2WashError::Authentication(report)
+            | Pay2WashError::Http(report)
+            | Pay2WashError::Json(report)
+            | Pay2WashError::HtmlExtraction(report)
+            | Pay2WashError::StateInvariant(report) => Debug::fmt(report, f),
+        }
+    }
+}
+
+impl Pay2WashError {
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            // A fresh login is all it takes to recover from a bad session
+            Pay2WashError::BadSession => ErrorClass::Transient,
+            Pay2WashError::Authentication(_) => ErrorClass::Credential,
+            Pay2WashError::Http(report) => {
+                let error = report
+                    .chain()
+                    .find_map(|error| error.downcast_ref::<reqwest::Error>());
+
+                // A slow server is as likely to be back to normal by the next attempt
+                if error.is_some_and(reqwest::Error::is_timeout) {
+                    return ErrorClass::Transient;
+                }
+
+                match error.and_then(reqwest::Error::status) {
+                    // The endpoint no longer exists or expects a different request
+                    Some(status)
+                        if status.is_client_error() && status != StatusCode::TOO_MANY_REQUESTS =>
+                    {
+                        ErrorClass::Schema
+                    }
+                    _ => ErrorClass::Transient,
+                }
+            }
+            Pay2WashError::Json(_)
+            | Pay2WashError::HtmlExtraction(_)
+            | Pay2WashError::StateInvariant(_) => ErrorClass::Schema,
+        }
+    }
+}
+
+/// The URLs of every pay2wash page the client interacts with, derived from a
+/// base URL and an optional tenant subdomain.
+#[derive(Debug, Clone)]
+pub struct Pay2WashEndpoints {
+    pub login: Url,
+    pub home: Url,
+    pub logout: Url,
+    machine_statuses: Url,
 }
 
-const LOGIN_PAGE: &str = "https://holland2stay.pay2wash.app/login";
+impl Pay2WashEndpoints {
+    /// Build the endpoints for a tenant, eg. a `base_url` of `https://pay2wash.app`
+    /// with the tenant `holland2stay` results in `https://holland2stay.pay2wash.app/login`.
+    ///
+    /// A tenant of `None` uses the base URL as is, which is useful for local servers.
+    pub fn new(base_url: &Url, tenant: Option<&str>) -> color_eyre::Result<Self> {
+        let mut base_url = base_url.clone();
+
+        if let Some(tenant) = tenant {
+            let host = base_url
+                .host_str()
+                .ok_or_else(|| eyre!("base url {base_url} does not have a host"))?;
+
+            base_url
+                .set_host(Some(&format!("{tenant}.{host}")))
+                .wrap_err_with(|| format!("tenant {tenant:?} is not a valid subdomain"))?;
+        }
+
+        // Ensure relative endpoints are joined onto the base url instead of replacing its last segment
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+
+        let endpoint = |path: &str| {
+            base_url
+                .join(path)
+                .wrap_err_with(|| format!("failed to join {path:?} onto base url {base_url}"))
+        };
+
+        Ok(Self {
+            login: endpoint("login")?,
+            home: endpoint("home")?,
+            logout: endpoint("logout")?,
+            machine_statuses: endpoint("machine_statuses/")?,
+        })
+    }
+
+    pub fn machine_statuses(&self, location: &str) -> color_eyre::Result<Url> {
+        self.machine_statuses
+            .join(location)
+            .wrap_err_with(|| format!("location {location:?} is not a valid path segment"))
+    }
+}
 
 impl Pay2WashClient {
-    pub fn new(email: Email, password: Password) -> Self {
+    pub fn new(
+        endpoints: Pay2WashEndpoints,
+        email: Email,
+        password: Password,
+        max_redirects: usize,
+        request_timeout: Duration,
+        mappings_refresh_interval: Duration,
+        archive: Option<Archive>,
+    ) -> Self {
+        let machine_statuses_path = endpoints.machine_statuses.path().to_owned();
+        let cookies = Arc::new(Jar::default());
+
         Self {
+            endpoints,
             email,
             password,
             http_client: reqwest::Client::builder()
-                .cookie_store(true)
-                .redirect(redirect::Policy::custom(|attempt| {
-                    if attempt.previous().len() == 5
+                .cookie_provider(Arc::clone(&cookies))
+                .timeout(request_timeout)
+                .redirect(redirect::Policy::custom(move |attempt| {
+                    if attempt.previous().len() >= max_redirects
                         || attempt
                             .previous()
                             .last()
                             .expect("chain should have at least one url")
                             .path()
-                            .starts_with("/machine_statuses/")
+                            .starts_with(&machine_statuses_path)
                     {
-                        // Do not redirect if chain is longer than 5 redirects
+                        // Do not redirect if chain is `max_redirects` redirects long
                         // or request is to "api" routes
                         attempt.stop()
                     } else {
@@ -70,26 +220,56 @@ impl Pay2WashClient {
                 }))
                 .build()
                 .expect("reqwest client configuration should be valid"),
+            cookies,
+            mappings_refresh_interval,
+            archive,
         }
     }
 
+    /// Read the body of `response`, keeping a copy in the archive if there is one
+    async fn receive(
+        &self,
+        response: reqwest::Response,
+        kind: SnapshotKind,
+    ) -> Result<String, Pay2WashError> {
+        let url = response.url().to_string();
+        let status = response.status().as_u16();
+
+        let body = response
+            .text()
+            .await
+            .wrap_err("failed to receive response from server")
+            .map_err(Pay2WashError::Http)?;
+
+        if let Some(archive) = &self.archive {
+            archive.capture(Snapshot {
+                captured_at: unix_timestamp(),
+                kind,
+                url,
+                status,
+                body: body.clone(),
+            });
+        }
+
+        Ok(body)
+    }
+
     #[tracing::instrument]
-    pub async fn authenticate(&self) -> color_eyre::Result<AuthenticatedSession> {
-        trace!(LOGIN_PAGE, "fetching login form for CSRF token");
+    pub async fn authenticate(&self) -> Result<AuthenticatedSession, Pay2WashError> {
+        trace!(login_page = %self.endpoints.login, "fetching login form for CSRF token");
 
         let response = self
             .http_client
-            .get(LOGIN_PAGE)
+            .get(self.endpoints.login.clone())
             .send()
             .await
-            .wrap_err("failed to GET `/login` form")?
+            .wrap_err("failed to GET `/login` form")
+            .map_err(Pay2WashError::Http)?
             .error_for_status()
-            .wrap_err("server responded with non-success status code")?;
+            .wrap_err("server responded with non-success status code")
+            .map_err(Pay2WashError::Http)?;
 
-        let document = response
-            .text()
-            .await
-            .wrap_err("failed to receive response from server")?;
+        let document = self.receive(response, SnapshotKind::Html).await?;
 
         trace!("received login form");
 
@@ -99,7 +279,8 @@ impl Pay2WashClient {
 
         let session = extract_session(html)
             .wrap_err("failed to extract session information from document")
-            .note("the html returned by the server may have changed")?;
+            .note("the html returned by the server may have changed")
+            .map_err(Pay2WashError::HtmlExtraction)?;
 
         trace!("extracted session information from login form");
 
@@ -119,7 +300,7 @@ impl Pay2WashClient {
     pub async fn authenticate_from_unauthenticated_session(
         &self,
         session: UnauthenticatedSession,
-    ) -> color_eyre::Result<AuthenticatedSession> {
+    ) -> Result<AuthenticatedSession, Pay2WashError> {
         #[derive(Serialize, Debug)]
         struct LoginForm<'s> {
             _token: &'s str,
@@ -133,24 +314,23 @@ impl Pay2WashClient {
             password: self.password.as_ref(),
         };
 
-        trace!(?login_form, LOGIN_PAGE, "submitting login form");
+        trace!(?login_form, login_page = %self.endpoints.login, "submitting login form");
 
         let response = self
             .http_client
-            .post(LOGIN_PAGE)
+            .post(self.endpoints.login.clone())
             .form(&login_form)
             .send()
             .await
-            .wrap_err("failed to POST `/login` form")?
+            .wrap_err("failed to POST `/login` form")
+            .map_err(Pay2WashError::Http)?
             .error_for_status()
-            .wrap_err("server responded with non-success status code")?;
+            .wrap_err("server responded with non-success status code")
+            .map_err(Pay2WashError::Http)?;
 
         trace!("login form submitted successfully");
 
-        let document = response
-            .text()
-            .await
-            .wrap_err("failed to receive response from server")?;
+        let document = self.receive(response, SnapshotKind::Html).await?;
 
         trace!("received webpage html");
 
@@ -160,67 +340,275 @@ impl Pay2WashClient {
 
         let session = extract_session(html)
             .wrap_err("failed to extract session information from document")
-            .note("the html returned by the server may have changed")?;
+            .note("the html returned by the server may have changed")
+            .map_err(Pay2WashError::HtmlExtraction)?;
 
         trace!("extracted session information from login form");
 
         match session {
             Pay2WashSession::Authenticated(authenticated_session) => Ok(authenticated_session),
-            _ => bail!("failed to achieve an authenticated sessions"),
+            _ => Err(Pay2WashError::Authentication(
+                eyre!("failed to achieve an authenticated sessions")
+                    .suggestion("check that the provided email and password are correct"),
+            )),
         }
     }
 
+    /// De-authenticate the session, consuming it since it can no longer be used afterwards
     #[tracing::instrument]
-    pub async fn get_machine_statuses<'session>(
-        &self,
-        session: &'session AuthenticatedSession,
-    ) -> Result<HashMap<&'session str, MachineStatus>, AuthenticatedSessionError> {
+    pub async fn logout(&self, session: AuthenticatedSession) -> color_eyre::Result<()> {
+        trace!(logout_page = %self.endpoints.logout, "logging out");
+
         let response = self
             .http_client
-            .get(format!(
-                "https://holland2stay.pay2wash.app/machine_statuses/{}",
-                session.location
-            ))
+            .get(self.endpoints.logout.clone())
             .send()
             .await
-            .wrap_err("failed to GET `/machine_statuses/{ID}`")?
+            .wrap_err("failed to GET `/logout`")?
             .error_for_status()
             .wrap_err("server responded with non-success status code")?;
 
+        // The server redirects back to the login page once the session is de-authenticated
+        if response.url().path() != self.endpoints.login.path() {
+            bail!(
+                "logout redirected to {} instead of {}",
+                response.url(),
+                self.endpoints.login
+            );
+        }
+
+        trace!("logged out");
+
+        Ok(())
+    }
+
+    /// Get the status of every machine at the session's location, refreshing the machine
+    /// mappings first when they are due or the statuses include a machine missing from them
+    #[tracing::instrument]
+    pub async fn get_machine_statuses(
+        &self,
+        session: &mut AuthenticatedSession,
+    ) -> Result<HashMap<String, MachineStatus>, Pay2WashError> {
+        if session.mappings_refreshed_at.elapsed() >= self.mappings_refresh_interval {
+            self.try_refresh_machine_mappings(session).await?;
+        }
+
+        let url = self
+            .endpoints
+            .machine_statuses(&session.location)
+            .map_err(Pay2WashError::HtmlExtraction)?;
+
+        let response = self
+            .http_client
+            .get(url)
+            .send()
+            .await
+            .wrap_err("failed to GET `/machine_statuses/{ID}`")
+            .map_err(Pay2WashError::Http)?
+            .error_for_status()
+            .wrap_err("server responded with non-success status code")
+            .map_err(Pay2WashError::Http)?;
+
         if response.status().is_redirection() {
-            return Err(AuthenticatedSessionError::BadSession);
+            return Err(Pay2WashError::BadSession);
         }
 
-        let document = response
-            .text()
+        let document = self
+            .receive(response, SnapshotKind::MachineStatuses)
+            .await?;
+
+        let statuses = parse_machine_statuses(&document)?;
+
+        let unknown: Vec<&str> = statuses
+            .keys()
+            .copied()
+            .filter(|&key| !session.machine_mappings.contains_key(key))
+            .collect();
+
+        // Machines a refresh already failed to find are not refreshed for again on every scrape
+        if unknown
+            .iter()
+            .any(|&key| !session.unmapped_machines.contains(key))
+        {
+            info!(?unknown, "machine statuses include unknown machines, refreshing machine mappings");
+
+            self.try_refresh_machine_mappings(session).await?;
+
+            session.unmapped_machines = unknown
+                .into_iter()
+                .filter(|&key| !session.machine_mappings.contains_key(key))
+                .map(str::to_owned)
+                .collect();
+
+            if !session.unmapped_machines.is_empty() {
+                warn!(
+                    unmapped = ?session.unmapped_machines,
+                    "machines are missing from the home page, reporting them by their id"
+                );
+            }
+        }
+
+        name_machine_statuses(statuses, &session.machine_mappings)
+    }
+
+    /// Refresh the machine mappings of `session`, keeping the current ones when that fails for
+    /// any other reason than the session having gone bad
+    async fn try_refresh_machine_mappings(
+        &self,
+        session: &mut AuthenticatedSession,
+    ) -> Result<(), Pay2WashError> {
+        match self.refresh_machine_mappings(session).await {
+            Err(Pay2WashError::BadSession) => Err(Pay2WashError::BadSession),
+            Err(error) => {
+                // The statuses can still be decoded, only new machines end up under their id
+                warn!(?error, "failed to refresh machine mappings, keeping the current ones");
+
+                Ok(())
+            }
+            Ok(()) => Ok(()),
+        }
+    }
+
+    /// Replace the machine mappings of `session` with the machines currently listed on `/home`,
+    /// failing with [`Pay2WashError::BadSession`] if the account was moved to another location
+    #[tracing::instrument(skip_all)]
+    pub async fn refresh_machine_mappings(
+        &self,
+        session: &mut AuthenticatedSession,
+    ) -> Result<(), Pay2WashError> {
+        trace!(home_page = %self.endpoints.home, "fetching home page for machine mappings");
+
+        // Even a failed refresh is not retried before the next interval
+        session.mappings_refreshed_at = Instant::now();
+
+        let response = self
+            .http_client
+            .get(self.endpoints.home.clone())
+            .send()
             .await
-            .wrap_err("failed to receive response from server")?;
-
-        let statuses: HashMap<&str, JsonMachineStatus> = serde_json::from_str(&document)
-            .wrap_err("failed to deserialize json data from server")
-            .with_section(|| document.clone().header("JSON"))?;
-
-        let statuses = statuses
-            .into_iter()
-            .map(|(key, value)| {
-                if let Some(new_key) = session.machine_mappings.get(key) {
-                    Ok((
-                        new_key.as_str(),
-                        MachineStatus {
-                            state: MachineState::try_from(&value).wrap_err_with(|| {
-                                format!("encountered problem decoding machine status: {value:?}")
-                            })?,
-                            raw: value,
-                        },
-                    ))
-                } else {
-                    Err(eyre!("key {key} is not in machine_mappings"))
-                }
-            })
-            .collect::<color_eyre::Result<_>>()?;
+            .wrap_err("failed to GET `/home`")
+            .map_err(Pay2WashError::Http)?
+            .error_for_status()
+            .wrap_err("server responded with non-success status code")
+            .map_err(Pay2WashError::Http)?;
 
-        Ok(statuses)
+        let document = self.receive(response, SnapshotKind::Html).await?;
+
+        let refreshed = match extract_session(Html::parse_document(&document)) {
+            Ok(Pay2WashSession::Authenticated(refreshed)) => refreshed,
+            // Redirected to the login page
+            Ok(Pay2WashSession::Unauthenticated(_)) => return Err(Pay2WashError::BadSession),
+            Err(error) => {
+                // The mappings at hand are still good for every machine but the new ones
+                warn!(?error, "failed to extract machine mappings from home page");
+
+                return Ok(());
+            }
+        };
+
+        // The mappings are of the new location, logging in again picks up everything else about it
+        if refreshed.location != session.location {
+            warn!(
+                previous = session.location,
+                current = refreshed.location,
+                "account moved to another location"
+            );
+
+            return Err(Pay2WashError::BadSession);
+        }
+
+        if refreshed.machine_mappings != session.machine_mappings {
+            info!(
+                previous = session.machine_mappings.len(),
+                current = refreshed.machine_mappings.len(),
+                "machine mappings changed"
+            );
+        }
+
+        session.csrf_token = refreshed.csrf_token;
+        session.machine_mappings = refreshed.machine_mappings;
+
+        Ok(())
     }
+
+    /// `session` along with the cookies keeping it authenticated, if there are any
+    pub fn store_session(&self, session: &AuthenticatedSession) -> Option<StoredSession> {
+        let cookies = self.cookies.cookies(&self.endpoints.login)?;
+
+        Some(StoredSession {
+            cookies: cookies.to_str().ok()?.to_owned(),
+            csrf_token: session.csrf_token.clone(),
+            user_token: session.user_token,
+            location: session.location.clone(),
+            machine_mappings: session.machine_mappings.clone(),
+        })
+    }
+
+    /// Resume a stored session, which may well have expired since, getting the machine statuses
+    /// with it tells
+    pub fn restore_session(&self, stored: StoredSession) -> AuthenticatedSession {
+        for cookie in stored.cookies.split("; ") {
+            self.cookies
+                .add_cookie_str(&format!("{cookie}; Path=/"), &self.endpoints.login);
+        }
+
+        AuthenticatedSession {
+            csrf_token: stored.csrf_token,
+            user_token: stored.user_token,
+            location: stored.location,
+            machine_mappings: stored.machine_mappings,
+            mappings_refreshed_at: Instant::now(),
+            unmapped_machines: HashSet::new(),
+        }
+    }
+}
+
+/// Decode a `machine_statuses` response, naming every machine after its entry in `machine_mappings`
+pub fn decode_machine_statuses(
+    document: &str,
+    machine_mappings: &HashMap<String, Machine>,
+) -> Result<HashMap<String, MachineStatus>, Pay2WashError> {
+    name_machine_statuses(parse_machine_statuses(document)?, machine_mappings)
+}
+
+/// The raw status of every machine in a `machine_statuses` response by machine id
+fn parse_machine_statuses(
+    document: &str,
+) -> Result<HashMap<&str, JsonMachineStatus>, Pay2WashError> {
+    serde_json::from_str(document)
+        .wrap_err("failed to deserialize json data from server")
+        .with_section(|| document.to_owned().header("JSON"))
+        .map_err(Pay2WashError::Json)
+}
+
+/// Name every machine after its entry in `machine_mappings`, machines missing from it keep
+/// their id as name
+fn name_machine_statuses(
+    statuses: HashMap<&str, JsonMachineStatus>,
+    machine_mappings: &HashMap<String, Machine>,
+) -> Result<HashMap<String, MachineStatus>, Pay2WashError> {
+    statuses
+        .into_iter()
+        .map(|(key, value)| {
+            let machine = machine_mappings
+                .get(key)
+                .cloned()
+                .unwrap_or_else(|| Machine::new(key.to_owned()));
+
+            Ok((
+                machine.name,
+                MachineStatus {
+                    kind: machine.kind,
+                    state: MachineState::try_from(&value)
+                        .wrap_err_with(|| {
+                            format!("encountered problem decoding machine status: {value:?}")
+                        })
+                        .map_err(Pay2WashError::StateInvariant)?,
+                    raw: value,
+                },
+            ))
+        })
+        .collect()
 }
 
 #[derive(Debug)]
@@ -239,7 +627,23 @@ pub struct AuthenticatedSession {
     pub csrf_token: String,
     pub user_token: UserId,
     pub location: String,
-    pub machine_mappings: HashMap<String, String>,
+    pub machine_mappings: HashMap<String, Machine>,
+    /// When the machine mappings were last taken from a page
+    pub mappings_refreshed_at: Instant,
+    /// Ids of machines with a status that were still missing from the machine mappings after
+    /// refreshing them
+    pub unmapped_machines: HashSet<String>,
+}
+
+/// An [`AuthenticatedSession`] and its cookies, which are as good as the credentials
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StoredSession {
+    /// `name=value` pairs as sent in the `Cookie` header
+    cookies: String,
+    csrf_token: String,
+    user_token: UserId,
+    location: String,
+    machine_mappings: HashMap<String, Machine>,
 }
 
 impl Pay2WashSession {
@@ -317,7 +721,7 @@ pub(crate) fn extract_session(html: Html) -> color_eyre::Result<Pay2WashSession>
                         })
                         .with_section(|| format!("{:?}", element.value()).header("Element:"))?
                         .to_owned(),
-                    {
+                    Machine::new({
                         let parent =
                             element.parent().and_then(ElementRef::wrap).ok_or_else(|| {
                                 eyre!("element does not have have parent").with_section(|| {
@@ -343,10 +747,10 @@ pub(crate) fn extract_session(html: Html) -> color_eyre::Result<Pay2WashSession>
                             })?
                             .trim()
                             .to_owned()
-                    },
+                    }),
                 ))
             })
-            .collect::<color_eyre::Result<HashMap<String, String>>>()?;
+            .collect::<color_eyre::Result<HashMap<String, Machine>>>()?;
 
         Ok(Pay2WashSession::Authenticated(AuthenticatedSession {
             csrf_token,
@@ -355,6 +759,60 @@ pub(crate) fn extract_session(html: Html) -> color_eyre::Result<Pay2WashSession>
                 .wrap_err("user_token was a non-integer")?,
             location: location.to_owned(),
             machine_mappings,
+            mappings_refreshed_at: Instant::now(),
+            unmapped_machines: HashSet::new(),
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{model::MachineKind, *};
+
+    const STATUSES: &str = r#"{
+        "101": {"running": true, "starter": 42, "reserved": false, "reserver": 0, "in_maintenance": 0, "remaining_time": "00:45", "gateway_offline": 0, "remaining_time_is_from_machine": 1, "controller_logic": 0},
+        "102": {"running": false, "starter": 0, "reserved": false, "reserver": 0, "in_maintenance": 0, "remaining_time": "00:00", "gateway_offline": 0, "remaining_time_is_from_machine": 0, "controller_logic": 0}
+    }"#;
+
+    fn mappings() -> HashMap<String, Machine> {
+        HashMap::from([(String::from("101"), Machine::new(String::from("W1")))])
+    }
+
+    #[test]
+    fn names_machines_after_their_mapping() {
+        let statuses =
+            decode_machine_statuses(STATUSES, &mappings()).expect("statuses should decode");
+
+        let washer = &statuses["W1"];
+        assert_eq!(washer.kind, MachineKind::Washer);
+        assert!(matches!(washer.state, MachineState::Running { .. }));
+    }
+
+    #[test]
+    fn unmapped_machines_keep_their_id() {
+        let statuses =
+            decode_machine_statuses(STATUSES, &mappings()).expect("statuses should decode");
+
+        let unmapped = &statuses["102"];
+        assert_eq!(unmapped.kind, MachineKind::Unknown);
+        assert!(matches!(unmapped.state, MachineState::Idle));
+    }
+
+    #[test]
+    fn rejects_malformed_json() {
+        assert!(matches!(
+            decode_machine_statuses(r#"{"101": {"running": true}}"#, &mappings()),
+            Err(Pay2WashError::Json(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_impossible_states() {
+        let statuses = STATUSES.replacen(r#""in_maintenance": 0"#, r#""in_maintenance": 1"#, 1);
+
+        assert!(matches!(
+            decode_machine_statuses(&statuses, &mappings()),
+            Err(Pay2WashError::StateInvariant(_))
+        ));
+    }
+}
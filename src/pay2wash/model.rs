@@ -1,6 +1,6 @@
 use serde::{
     de::{self, Visitor},
-    Deserialize,
+    Deserialize, Serialize,
 };
 use thiserror::Error;
 
@@ -26,11 +26,56 @@ pub struct JsonMachineStatus {
 
 #[derive(Debug)]
 pub struct MachineStatus {
+    pub kind: MachineKind,
     pub state: MachineState,
     pub raw: JsonMachineStatus,
 }
 
-#[derive(Debug, Clone, Copy)]
+/// A machine as listed on the home page
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Machine {
+    pub name: String,
+    pub kind: MachineKind,
+}
+
+impl Machine {
+    pub fn new(name: String) -> Self {
+        Self {
+            kind: MachineKind::from_name(&name),
+            name,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MachineKind {
+    Washer,
+    Dryer,
+    Unknown,
+}
+
+impl MachineKind {
+    /// Machines are named after their kind followed by a number, eg. `W1` or `D3`
+    pub fn from_name(name: &str) -> Self {
+        match name.trim().chars().next().map(|char| char.to_ascii_uppercase()) {
+            Some('W') => MachineKind::Washer,
+            Some('D') => MachineKind::Dryer,
+            _ => MachineKind::Unknown,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MachineKind::Washer => "washer",
+            MachineKind::Dryer => "dryer",
+            MachineKind::Unknown => "unknown",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
 pub enum MachineState {
     Running {
         starter: UserId,
@@ -83,7 +128,7 @@ impl TryFrom<&JsonMachineStatus> for MachineState {
     }
 }
 
-#[derive(Debug, Deserialize, Clone, Copy)]
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
 #[serde(transparent)]
 pub struct UserId(u32);
 
@@ -140,6 +185,19 @@ impl TryFrom<NumberBool> for bool {
     }
 }
 
+impl Serialize for NumberBool {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            NumberBool::False => serializer.serialize_bool(false),
+            NumberBool::True => serializer.serialize_bool(true),
+            NumberBool::Unknown(value) => serializer.serialize_u8(*value),
+        }
+    }
+}
+
 impl<'de> Deserialize<'de> for NumberBool {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -215,6 +273,16 @@ impl From<RemainingTime> for Duration {
     }
 }
 
+/// Serialized as a number of seconds
+impl Serialize for RemainingTime {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_u64(self.0.as_secs())
+    }
+}
+
 impl<'de> Deserialize<'de> for RemainingTime {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -0,0 +1,123 @@
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+use color_eyre::eyre::Context;
+use reqwest::Url;
+use scraper::Html;
+use tokio::{sync::mpsc, time::sleep};
+use tracing::{info, warn};
+
+use crate::{
+    archive::{self, SnapshotKind},
+    pay2wash::{decode_machine_statuses, extract_session, AuthenticatedSession, Pay2WashSession},
+    scrape::Pipeline,
+    shutdown::Shutdown,
+};
+
+/// Feed every snapshot archived in `directory` through the same decoding and recording as a
+/// live scrape, waiting between snapshots as long as the scraper did divided by `speed`
+///
+/// A `speed` of 0 replays the snapshots as fast as possible. Once every snapshot has been
+/// replayed the final state is kept around until shutdown.
+pub async fn replay(
+    directory: PathBuf,
+    speed: u32,
+    mut pipeline: Pipeline,
+    mut shutdown: Shutdown,
+) -> color_eyre::Result<()> {
+    let (sender, mut receiver) = mpsc::channel(64);
+
+    let reader = tokio::task::spawn_blocking(move || -> color_eyre::Result<()> {
+        for snapshot in archive::read_archive(&directory)? {
+            if sender.blocking_send(snapshot).is_err() {
+                // Replay was stopped
+                break;
+            }
+        }
+
+        Ok(())
+    });
+
+    // Archives of several accounts interleave, so sessions are kept apart per location
+    let mut sessions: HashMap<String, AuthenticatedSession> = HashMap::new();
+    let mut previous_capture: Option<i64> = None;
+    let mut replayed: u64 = 0;
+
+    loop {
+        let snapshot = tokio::select! {
+            snapshot = receiver.recv() => snapshot,
+            () = shutdown.wait() => return Ok(()),
+        };
+
+        let Some(snapshot) = snapshot else { break };
+
+        if let Some(previous_capture) = previous_capture {
+            let elapsed = u64::try_from(snapshot.captured_at - previous_capture).unwrap_or(0);
+
+            if let Some(delay) = Duration::from_secs(elapsed).checked_div(speed) {
+                tokio::select! {
+                    () = sleep(delay) => {},
+                    () = shutdown.wait() => return Ok(()),
+                }
+            }
+        }
+
+        previous_capture = Some(snapshot.captured_at);
+
+        match snapshot.kind {
+            // Login and home pages carry the machine mappings needed to decode what follows
+            SnapshotKind::Html => match extract_session(Html::parse_document(&snapshot.body)) {
+                Ok(Pay2WashSession::Authenticated(authenticated_session)) => {
+                    info!(
+                        location = authenticated_session.location,
+                        captured_at = snapshot.captured_at,
+                        "replaying authenticated session"
+                    );
+
+                    sessions.insert(authenticated_session.location.clone(), authenticated_session);
+                }
+                Ok(Pay2WashSession::Unauthenticated(_)) => {}
+                Err(error) => {
+                    warn!(?error, url = snapshot.url, "failed to extract session from archived page");
+                }
+            },
+            SnapshotKind::MachineStatuses => {
+                let location = Url::parse(&snapshot.url)
+                    .ok()
+                    .and_then(|url| Some(url.path_segments()?.next_back()?.to_owned()));
+
+                let Some(session) = location.and_then(|location| sessions.get(&location)) else {
+                    warn!(
+                        captured_at = snapshot.captured_at,
+                        url = snapshot.url,
+                        "skipping machine statuses archived before any authenticated page of their location"
+                    );
+
+                    continue;
+                };
+
+                match decode_machine_statuses(&snapshot.body, &session.machine_mappings) {
+                    Ok(statuses) => {
+                        pipeline.record(session, &statuses, snapshot.captured_at);
+
+                        replayed += 1;
+                    }
+                    Err(error) => {
+                        warn!(
+                            ?error,
+                            captured_at = snapshot.captured_at,
+                            "failed to decode archived machine statuses"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    reader.await.wrap_err("archive reader panicked")??;
+
+    info!(replayed, "replay finished, serving the final state until shutdown");
+
+    shutdown.wait().await;
+
+    Ok(())
+}
@@ -0,0 +1,597 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Write},
+    sync::atomic::AtomicI64,
+    time::Duration,
+};
+
+use prometheus_client::{
+    encoding::{EncodeLabelSet, EncodeLabelValue, LabelValueEncoder},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::{Registry, Unit},
+};
+use tokio::time::{sleep_until, timeout, Instant};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    backoff::Backoff,
+    config::Account,
+    events::EventEngine,
+    notify::Notifier,
+    occupancy::Occupancy,
+    pay2wash::{
+        model::MachineStatus, AuthenticatedSession, ErrorClass, Pay2WashClient, Pay2WashError,
+    },
+    session_cache::SessionCache,
+    shutdown::Shutdown,
+    status::StatusBoard,
+    time::unix_timestamp,
+    LocationMetricKey, MachineStateLabel, MachineStateMetricKey, Metrics, UserTokenMetricKey,
+    WashingMachineMetricKey,
+};
+
+/// Time given to logging out on shutdown, well within the 5s fly waits before killing the process
+const SHUTDOWN_LOGOUT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Metrics about the scrapes themselves, shared by the scrapers of every account
+#[derive(Debug, Clone)]
+pub struct ScrapeMetrics {
+    duration: Family<AccountMetricKey, Histogram, fn() -> Histogram>,
+    successes: Family<AccountMetricKey, Counter>,
+    failures: Family<ScrapeFailureMetricKey, Counter>,
+    consecutive_failures: Family<AccountMetricKey, Gauge<i64, AtomicI64>>,
+    reauthentications: Family<AccountMetricKey, Counter>,
+    last_success: Family<LocationMetricKey, Gauge<i64, AtomicI64>>,
+}
+
+impl Default for ScrapeMetrics {
+    fn default() -> Self {
+        Self {
+            // 50ms up to ~25s
+            duration: Family::new_with_constructor(|| {
+                Histogram::new(exponential_buckets(0.05, 2.0, 10))
+            }),
+            successes: Family::default(),
+            failures: Family::default(),
+            consecutive_failures: Family::default(),
+            reauthentications: Family::default(),
+            last_success: Family::default(),
+        }
+    }
+}
+
+impl ScrapeMetrics {
+    pub fn register(&self, registry: &mut Registry) {
+        registry.register_with_unit(
+            "scrape_duration",
+            "time taken by a scrape, including authentication if it was needed",
+            Unit::Seconds,
+            self.duration.clone(),
+        );
+
+        registry.register(
+            "scrape_successes",
+            "the number of scrapes that completed successfully",
+            self.successes.clone(),
+        );
+
+        registry.register(
+            "scrape_failures",
+            "the number of scrapes that failed, by the kind of error encountered",
+            self.failures.clone(),
+        );
+
+        registry.register(
+            "scrape_consecutive_failures",
+            "the number of scrapes of an account that have failed in a row, anything above 0 means the exported machine data of its location is stale",
+            self.consecutive_failures.clone(),
+        );
+
+        registry.register(
+            "reauthentications",
+            "the number of times the pay2wash session went bad and had to be re-authenticated",
+            self.reauthentications.clone(),
+        );
+
+        registry.register_with_unit(
+            "scrape_last_success_timestamp",
+            "the UNIX timestamp of the last successful scrape per location",
+            Unit::Seconds,
+            self.last_success.clone(),
+        );
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+pub struct AccountMetricKey {
+    /// Name of the scraped account, never its email
+    pub account: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, EncodeLabelSet)]
+pub struct ScrapeFailureMetricKey {
+    pub account: String,
+    pub class: ScrapeErrorLabel,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ScrapeErrorLabel {
+    Auth,
+    BadSession,
+    Http,
+    Json,
+    HtmlExtraction,
+    StateInvariant,
+}
+
+impl From<&Pay2WashError> for ScrapeErrorLabel {
+    fn from(error: &Pay2WashError) -> Self {
+        match error {
+            Pay2WashError::BadSession => ScrapeErrorLabel::BadSession,
+            Pay2WashError::Authentication(_) => ScrapeErrorLabel::Auth,
+            Pay2WashError::Http(_) => ScrapeErrorLabel::Http,
+            Pay2WashError::Json(_) => ScrapeErrorLabel::Json,
+            Pay2WashError::HtmlExtraction(_) => ScrapeErrorLabel::HtmlExtraction,
+            Pay2WashError::StateInvariant(_) => ScrapeErrorLabel::StateInvariant,
+        }
+    }
+}
+
+impl EncodeLabelValue for ScrapeErrorLabel {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> Result<(), fmt::Error> {
+        encoder.write_str(match self {
+            ScrapeErrorLabel::Auth => "auth",
+            ScrapeErrorLabel::BadSession => "bad_session",
+            ScrapeErrorLabel::Http => "http",
+            ScrapeErrorLabel::Json => "json",
+            ScrapeErrorLabel::HtmlExtraction => "html_extraction",
+            ScrapeErrorLabel::StateInvariant => "state_invariant",
+        })
+    }
+}
+
+/// How long to wait before retrying after a failed scrape, per [`ErrorClass`]
+#[derive(Debug)]
+struct RetryPolicy {
+    transient: Backoff,
+    credential: Backoff,
+    failures: i64,
+    /// Whether a schema error has been sent to Sentry since the last successful scrape
+    schema_error_reported: bool,
+    /// Time between two successful scrapes
+    interval: Duration,
+}
+
+impl RetryPolicy {
+    fn new(interval: Duration) -> Self {
+        Self {
+            transient: Backoff::new(Duration::from_secs(5), Duration::from_secs(10 * 60)),
+            // Repeatedly submitting bad credentials may get the account locked
+            credential: Backoff::new(
+                Duration::from_secs(5 * 60),
+                Duration::from_secs(6 * 60 * 60),
+            ),
+            failures: 0,
+            schema_error_reported: false,
+            interval,
+        }
+    }
+
+    fn success(&mut self) {
+        self.transient.reset();
+        self.credential.reset();
+        self.failures = 0;
+        self.schema_error_reported = false;
+    }
+
+    fn failure(&mut self, error: &Pay2WashError) -> Duration {
+        self.failures += 1;
+
+        match error.class() {
+            ErrorClass::Transient => {
+                warn!(?error, failures = self.failures, "scrape failed with a transient error");
+
+                self.transient.next_delay()
+            }
+            ErrorClass::Credential => {
+                error!(?error, failures = self.failures, "login credentials were rejected");
+
+                if self.credential.attempt() == 0 {
+                    sentry::capture_error(error);
+                }
+
+                self.credential.next_delay()
+            }
+            ErrorClass::Schema => {
+                error!(?error, failures = self.failures, "pay2wash responded with unexpected data");
+
+                // Retrying will not help until the scraper is updated, only report the first one
+                if !self.schema_error_reported {
+                    sentry::capture_error(error);
+                    self.schema_error_reported = true;
+                }
+
+                self.interval
+            }
+        }
+    }
+}
+
+/// Everything the decoded statuses of a scrape are recorded into
+#[derive(Debug)]
+pub struct Pipeline {
+    pub metrics: Metrics,
+    pub status_board: StatusBoard,
+    pub event_engine: EventEngine,
+    pub occupancy: Occupancy,
+    pub notifier: Option<Notifier>,
+    /// Scrapes of a location a machine can be missing from before its metrics are removed
+    pub stale_after: u64,
+}
+
+impl Pipeline {
+    /// Feed decoded `statuses` observed at `observed_at` into every part of the pipeline
+    pub fn record(
+        &mut self,
+        session: &AuthenticatedSession,
+        statuses: &HashMap<String, MachineStatus>,
+        observed_at: i64,
+    ) {
+        update_metrics(&self.metrics, session, statuses, observed_at, self.stale_after);
+
+        self.status_board.publish(
+            &session.location,
+            statuses.iter().map(|(name, status)| (name.as_str(), status)),
+            observed_at,
+        );
+
+        self.event_engine.observe(
+            &session.location,
+            statuses.iter().map(|(name, status)| (name.as_str(), status)),
+            observed_at,
+        );
+
+        self.occupancy.observe(
+            &session.location,
+            statuses.iter().map(|(name, status)| (name.as_str(), status)),
+            observed_at,
+        );
+
+        if let Some(notifier) = &self.notifier {
+            notifier.observe(session, statuses, observed_at);
+        }
+    }
+}
+
+/// Scrape a single account into `pipeline` every `interval` until shutdown, failures only ever
+/// hold up this account
+#[tracing::instrument(skip_all, fields(account = %account.email))]
+pub async fn scraper(
+    account: Account,
+    interval: Duration,
+    scrape_metrics: ScrapeMetrics,
+    session_cache: Option<SessionCache>,
+    mut pipeline: Pipeline,
+    mut shutdown: Shutdown,
+) -> color_eyre::Result<()> {
+    let Account {
+        email,
+        name,
+        client,
+    } = account;
+
+    let mut session: Option<AuthenticatedSession> = None;
+    let mut retry_policy = RetryPolicy::new(interval);
+
+    if let Some(session_cache) = &session_cache {
+        if let Some(stored) = session_cache.get(&email) {
+            let mut restored = client.restore_session(stored);
+            let refreshed_at = restored.mappings_refreshed_at;
+
+            // Checked right away, so an expired session is replaced before the first scrape
+            match client.get_machine_statuses(&mut restored).await {
+                Ok(_) => {
+                    info!(location = restored.location, "resumed cached pay2wash session");
+
+                    if restored.mappings_refreshed_at != refreshed_at {
+                        session_cache.save(&email, &client, &restored);
+                    }
+
+                    session = Some(restored);
+                }
+                Err(Pay2WashError::BadSession) => {
+                    info!("cached pay2wash session has expired, logging in");
+                }
+                Err(error) => {
+                    // Logging in would not get any further, the scrapes retry with the session
+                    warn!(?error, "failed to check cached pay2wash session, keeping it");
+
+                    session = Some(restored);
+                }
+            }
+        }
+    }
+
+    let account_key = AccountMetricKey {
+        account: name.clone(),
+    };
+
+    let mut scraped_location: Option<String> = None;
+    let mut next_scrape = Instant::now();
+
+    loop {
+        tokio::select! {
+            () = sleep_until(next_scrape) => {},
+            () = shutdown.wait() => break,
+        }
+
+        let started = Instant::now();
+
+        let result = tokio::select! {
+            result = scrape(
+                &client,
+                &mut session,
+                &mut pipeline,
+                session_cache.as_ref().map(|cache| (cache, email.as_str())),
+            ) => result,
+            // A slow pay2wash must not hold up shutting down
+            () = shutdown.wait() => break,
+        };
+
+        scrape_metrics
+            .duration
+            .get_or_create(&account_key)
+            .observe(started.elapsed().as_secs_f64());
+
+        match result {
+            Ok(location) => {
+                if scraped_location.as_ref() != Some(&location) {
+                    let previous_location = scraped_location.replace(location.clone());
+
+                    let abandoned = pipeline.metrics.move_account(
+                        &name,
+                        previous_location.as_deref(),
+                        &location,
+                    );
+
+                    if let Some(previous_location) = previous_location {
+                        pipeline.metrics.user_token.remove(&UserTokenMetricKey {
+                            location: previous_location.clone(),
+                            account: name.clone(),
+                        });
+
+                        if abandoned {
+                            info!(
+                                previous_location,
+                                location,
+                                "account moved to another location, removing its old metrics"
+                            );
+
+                            pipeline.metrics.remove_location(&previous_location);
+                            scrape_metrics.last_success.remove(&LocationMetricKey {
+                                location: previous_location,
+                            });
+                        } else {
+                            info!(
+                                previous_location,
+                                location,
+                                "account moved to another location, other accounts still scrape the old one"
+                            );
+                        }
+                    }
+                }
+
+                if let Some(session) = &session {
+                    pipeline
+                        .metrics
+                        .user_token
+                        .get_or_create(&UserTokenMetricKey {
+                            location: location.clone(),
+                            account: name.clone(),
+                        })
+                        .set(i64::from(u32::from(session.user_token)));
+                }
+
+                scrape_metrics.successes.get_or_create(&account_key).inc();
+                scrape_metrics
+                    .last_success
+                    .get_or_create(&LocationMetricKey { location })
+                    .set(unix_timestamp());
+
+                retry_policy.success();
+                next_scrape = started + interval;
+            }
+            Err(error) => {
+                scrape_metrics
+                    .failures
+                    .get_or_create(&ScrapeFailureMetricKey {
+                        account: account_key.account.clone(),
+                        class: ScrapeErrorLabel::from(&error),
+                    })
+                    .inc();
+
+                if let Pay2WashError::BadSession = error {
+                    scrape_metrics
+                        .reauthentications
+                        .get_or_create(&account_key)
+                        .inc();
+
+                    if let Some(session) = session.take() {
+                        // Make sure the server side session is torn down before creating a new one
+                        if let Err(error) = client.logout(session).await {
+                            warn!(?error, "failed to log out of bad session");
+                        }
+                    }
+                }
+
+                next_scrape = Instant::now() + retry_policy.failure(&error);
+            }
+        }
+
+        scrape_metrics
+            .consecutive_failures
+            .get_or_create(&account_key)
+            .set(retry_policy.failures);
+
+        debug!(delay = ?next_scrape - Instant::now(), "waiting for next update");
+    }
+
+    if let Some(session) = session.take() {
+        if let Some(session_cache) = &session_cache {
+            // Staying logged in lets the next start resume the session with its latest cookies
+            session_cache.save(&email, &client, &session);
+
+            info!("kept pay2wash session for the next start");
+        } else {
+            match timeout(SHUTDOWN_LOGOUT_TIMEOUT, client.logout(session)).await {
+                Ok(Ok(())) => info!("logged out of pay2wash session"),
+                Ok(Err(error)) => warn!(?error, "failed to log out while shutting down"),
+                Err(_) => warn!("timed out logging out while shutting down"),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Scrape the machine statuses into `pipeline`, returning the location that was scraped
+///
+/// A new session is saved to `session_cache` under the account it is paired with.
+async fn scrape(
+    client: &Pay2WashClient,
+    session: &mut Option<AuthenticatedSession>,
+    pipeline: &mut Pipeline,
+    session_cache: Option<(&SessionCache, &str)>,
+) -> Result<String, Pay2WashError> {
+    let authenticated_session = match session {
+        Some(authenticated_session) => authenticated_session,
+        None => {
+            let authenticated_session = client.authenticate().await?;
+
+            info!(location = authenticated_session.location, "authenticated with pay2wash");
+
+            if let Some((session_cache, account)) = session_cache {
+                session_cache.save(account, client, &authenticated_session);
+            }
+
+            session.insert(authenticated_session)
+        }
+    };
+
+    let refreshed_at = authenticated_session.mappings_refreshed_at;
+
+    let statuses = client.get_machine_statuses(authenticated_session).await?;
+
+    // Otherwise the next start would resume with the csrf token and machine mappings from before
+    if authenticated_session.mappings_refreshed_at != refreshed_at {
+        if let Some((session_cache, account)) = session_cache {
+            session_cache.save(account, client, authenticated_session);
+        }
+    }
+
+    pipeline.record(authenticated_session, &statuses, unix_timestamp());
+
+    Ok(authenticated_session.location.clone())
+}
+
+fn update_metrics(
+    metrics: &Metrics,
+    session: &AuthenticatedSession,
+    statuses: &HashMap<String, MachineStatus>,
+    observed_at: i64,
+    stale_after: u64,
+) {
+    let generation = metrics.next_generation(&session.location);
+
+    let location_key = LocationMetricKey {
+        location: session.location.clone(),
+    };
+
+    metrics
+        .updated
+        .get_or_create(&location_key)
+        .set(observed_at);
+
+    for (name, status) in statuses {
+        let metric_key = WashingMachineMetricKey {
+            location: session.location.clone(),
+            name: name.clone(),
+            kind: status.kind,
+        };
+
+        macro_rules! metric {
+            ($name:ident) => {
+                metrics
+                    .$name
+                    .get_or_create(&metric_key, generation)
+                    .set(status.raw.$name)
+            };
+            ($name:ident as i64) => {
+                metrics
+                    .$name
+                    .get_or_create(&metric_key, generation)
+                    .set(i64::from(status.raw.$name))
+            };
+            ($name:ident as u32 => i64) => {
+                metrics
+                    .$name
+                    .get_or_create(&metric_key, generation)
+                    .set(i64::from(u32::from(status.raw.$name)))
+            };
+        }
+
+        metric!(running);
+        metric!(starter as u32 => i64);
+
+        metrics.remaining_time.get_or_create(&metric_key, generation).set(
+            status
+                .raw
+                .remaining_time
+                .into_inner()
+                .as_secs()
+                .try_into()
+                .expect("remaining time should not overflow an i64"),
+        );
+
+        metric!(reserved);
+        metric!(reserver as u32 => i64);
+
+        metric!(in_maintenance);
+        metric!(gateway_offline);
+        metric!(remaining_time_is_from_machine);
+        metric!(controller_logic as i64);
+
+        let current_state = MachineStateLabel::from(&status.state);
+
+        for state in MachineStateLabel::ALL {
+            metrics
+                .state
+                .get_or_create(
+                    &MachineStateMetricKey {
+                        location: session.location.clone(),
+                        name: name.clone(),
+                        kind: status.kind,
+                        state,
+                    },
+                    generation,
+                )
+                .set(state == current_state);
+        }
+    }
+
+    // Machines that were removed or renamed would otherwise export their last values forever
+    let removed = metrics.remove_stale(&session.location, generation, stale_after);
+
+    if removed > 0 {
+        info!(
+            series = removed,
+            location = session.location,
+            "removed metrics of machines that are no longer listed"
+        );
+    }
+}
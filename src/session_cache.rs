@@ -0,0 +1,164 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug},
+    fs,
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use color_eyre::eyre::{ensure, eyre, Context};
+use tracing::{debug, warn};
+
+use crate::{
+    pay2wash::{AuthenticatedSession, Pay2WashClient, StoredSession},
+    strict_types::Secret,
+};
+
+/// Bound to every encrypted file, so a file of another format can never decrypt as this one
+const ASSOCIATED_DATA: &[u8] = b"pain2wash session cache v1";
+const NONCE_LENGTH: usize = 12;
+
+/// The pay2wash session of every account, encrypted on disk so a restart can resume them instead
+/// of logging in again
+#[derive(Clone)]
+pub struct SessionCache {
+    path: Arc<PathBuf>,
+    cipher: ChaCha20Poly1305,
+    /// Sessions by account email, the file is rewritten with all of them on every change
+    sessions: Arc<Mutex<HashMap<String, StoredSession>>>,
+}
+
+impl Debug for SessionCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionCache")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SessionCache {
+    /// Open the cache at `path` encrypted with `key`, 32 bytes encoded as base64, starting out
+    /// empty if the file does not exist or can not be decrypted
+    pub fn open(path: PathBuf, key: &Secret) -> color_eyre::Result<Self> {
+        let key = STANDARD
+            .decode(key.expose())
+            .ok()
+            .filter(|key| key.len() == 32)
+            .ok_or_else(|| eyre!("session_cache.key must be 32 bytes encoded as base64"))?;
+
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+
+        let sessions = match fs::read(&path) {
+            Ok(contents) => match decrypt(&cipher, &contents) {
+                Ok(sessions) => sessions,
+                Err(error) => {
+                    // Most likely the key changed, the sessions will be stored again after logging in
+                    warn!(?error, path = %path.display(), "failed to decrypt session cache, starting over");
+
+                    HashMap::new()
+                }
+            },
+            Err(error) if error.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(error) => {
+                return Err(error)
+                    .wrap_err_with(|| format!("failed to read session cache {}", path.display()))
+            }
+        };
+
+        debug!(sessions = sessions.len(), path = %path.display(), "opened session cache");
+
+        Ok(Self {
+            path: Arc::new(path),
+            cipher,
+            sessions: Arc::new(Mutex::new(sessions)),
+        })
+    }
+
+    /// The last session stored for `account`, which may well have expired since
+    pub fn get(&self, account: &str) -> Option<StoredSession> {
+        self.sessions
+            .lock()
+            .expect("session cache should not be poisoned")
+            .get(account)
+            .cloned()
+    }
+
+    /// Store `session` of `account` along with the current cookies of `client`, failing to write
+    /// it only costs a login after the next restart
+    pub fn save(&self, account: &str, client: &Pay2WashClient, session: &AuthenticatedSession) {
+        let Some(session) = client.store_session(session) else {
+            warn!("pay2wash session does not have any cookies to store");
+            return;
+        };
+
+        let mut sessions = self
+            .sessions
+            .lock()
+            .expect("session cache should not be poisoned");
+
+        sessions.insert(account.to_owned(), session);
+
+        if let Err(error) = self.write(&sessions) {
+            warn!(?error, path = %self.path.display(), "failed to write session cache");
+        }
+    }
+
+    fn write(&self, sessions: &HashMap<String, StoredSession>) -> color_eyre::Result<()> {
+        let plaintext = serde_json::to_vec(sessions).wrap_err("failed to serialize sessions")?;
+
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &plaintext,
+                    aad: ASSOCIATED_DATA,
+                },
+            )
+            .map_err(|_| eyre!("failed to encrypt sessions"))?;
+
+        let mut contents = nonce.to_vec();
+        contents.extend(ciphertext);
+
+        // Written next to the cache and moved over it, so a crash never leaves half a file behind
+        let temporary_path = self.path.with_extension("tmp");
+
+        fs::write(&temporary_path, contents)
+            .wrap_err_with(|| format!("failed to write {}", temporary_path.display()))?;
+        fs::rename(&temporary_path, &*self.path)
+            .wrap_err_with(|| format!("failed to replace {}", self.path.display()))?;
+
+        Ok(())
+    }
+}
+
+fn decrypt(
+    cipher: &ChaCha20Poly1305,
+    contents: &[u8],
+) -> color_eyre::Result<HashMap<String, StoredSession>> {
+    ensure!(
+        contents.len() >= NONCE_LENGTH,
+        "session cache is too short to hold a nonce"
+    );
+
+    let (nonce, ciphertext) = contents.split_at(NONCE_LENGTH);
+
+    let plaintext = cipher
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: ASSOCIATED_DATA,
+            },
+        )
+        .map_err(|_| eyre!("session cache was not encrypted with this key"))?;
+
+    serde_json::from_slice(&plaintext).wrap_err("session cache does not hold valid sessions")
+}
@@ -0,0 +1,58 @@
+use tokio::sync::watch;
+use tracing::info;
+
+/// Handle used to wait for a shutdown signal, cheap to clone for every task that
+/// needs to stop gracefully.
+#[derive(Debug, Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    /// Start listening for SIGINT and SIGTERM
+    pub fn listen() -> Self {
+        let (sender, receiver) = watch::channel(false);
+
+        tokio::spawn(async move {
+            signal().await;
+
+            info!("received shutdown signal, shutting down gracefully");
+
+            sender.send_replace(true);
+        });
+
+        Self(receiver)
+    }
+
+    /// Resolves once a shutdown has been requested
+    pub async fn wait(&mut self) {
+        while !*self.0.borrow_and_update() {
+            if self.0.changed().await.is_err() {
+                // The listener can only go away after a shutdown was requested
+                return;
+            }
+        }
+    }
+}
+
+async fn signal() {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install SIGINT handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = interrupt => {},
+        () = terminate => {},
+    }
+}
@@ -0,0 +1,80 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::pay2wash::model::{MachineKind, MachineState, MachineStatus, NumberBool};
+
+/// The latest decoded status of every machine, published by the scraper and read by the api
+#[derive(Debug, Clone)]
+pub struct StatusBoard {
+    reports: Arc<watch::Sender<Statuses>>,
+}
+
+pub type Statuses = BTreeMap<MachineKey, MachineReport>;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MachineKey {
+    pub location: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MachineReport {
+    pub location: String,
+    pub name: String,
+    pub kind: MachineKind,
+    #[serde(flatten)]
+    pub state: MachineState,
+    pub gateway_offline: NumberBool,
+    /// UNIX timestamp of the scrape this report came from
+    pub updated_at: i64,
+}
+
+impl Default for StatusBoard {
+    fn default() -> Self {
+        Self {
+            reports: Arc::new(watch::channel(Statuses::new()).0),
+        }
+    }
+}
+
+impl StatusBoard {
+    /// Replace every report for `location` with the freshly scraped `statuses`
+    pub fn publish<'s>(
+        &self,
+        location: &str,
+        statuses: impl IntoIterator<Item = (&'s str, &'s MachineStatus)>,
+        updated_at: i64,
+    ) {
+        self.reports.send_modify(|reports| {
+            reports.retain(|key, _| key.location != location);
+
+            reports.extend(statuses.into_iter().map(|(name, status)| {
+                (
+                    MachineKey {
+                        location: location.to_owned(),
+                        name: name.to_owned(),
+                    },
+                    MachineReport {
+                        location: location.to_owned(),
+                        name: name.to_owned(),
+                        kind: status.kind,
+                        state: status.state,
+                        gateway_offline: status.raw.gateway_offline,
+                        updated_at,
+                    },
+                )
+            }));
+        });
+    }
+
+    pub fn reports(&self) -> watch::Ref<'_, Statuses> {
+        self.reports.borrow()
+    }
+
+    /// Receiver that is notified every time a location is published
+    pub fn subscribe_reports(&self) -> watch::Receiver<Statuses> {
+        self.reports.subscribe()
+    }
+}
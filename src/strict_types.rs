@@ -48,3 +48,20 @@ impl Debug for Email {
         write!(f, "{:?}", self.0)
     }
 }
+
+/// A shared secret, such as a signing key, that is kept out of logs
+#[derive(Clone, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[hidden]")
+    }
+}
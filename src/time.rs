@@ -0,0 +1,11 @@
+use std::time::SystemTime;
+
+/// Seconds since the UNIX epoch, the timestamp used throughout the exported data
+pub fn unix_timestamp() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("time should only move forwards")
+        .as_secs()
+        .try_into()
+        .expect("unix timestamp should not overflow an i64")
+}
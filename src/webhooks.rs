@@ -0,0 +1,270 @@
+use std::{fs, path::Path, sync::Arc, time::Duration};
+
+use color_eyre::eyre::{bail, Context};
+use hmac::{Hmac, Mac};
+use regex::Regex;
+use reqwest::Url;
+use serde::{Deserialize, Deserializer};
+use sha2::Sha256;
+use tokio::{
+    sync::{
+        broadcast::error::RecvError,
+        mpsc::{self, error::TrySendError},
+    },
+    time::sleep,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    backoff::Backoff,
+    events::{Events, MachineEvent, MachineEventType},
+    pay2wash::model::MachineKind,
+    shutdown::Shutdown,
+    strict_types::Secret,
+    time::unix_timestamp,
+};
+
+/// Hex encoded HMAC-SHA256 of `{timestamp}.{body}`, keyed with the webhook's secret
+const SIGNATURE_HEADER: &str = "x-pain2wash-signature";
+/// UNIX timestamp of the delivery attempt, signed to keep deliveries from being replayed
+const TIMESTAMP_HEADER: &str = "x-pain2wash-timestamp";
+const EVENT_HEADER: &str = "x-pain2wash-event";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WebhooksFile {
+    #[serde(default)]
+    webhook: Vec<Webhook>,
+}
+
+/// An endpoint that is sent every event matching all of its filters, an empty filter
+/// matches everything
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Webhook {
+    pub url: Url,
+    pub secret: Secret,
+
+    #[serde(default)]
+    pub events: Vec<MachineEventType>,
+    #[serde(default)]
+    pub kinds: Vec<MachineKind>,
+    #[serde(default)]
+    pub locations: Vec<String>,
+    /// Regular expression the machine name has to match, eg. `^W[12]$`
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    pub name: Option<Regex>,
+
+    /// Attempts made to deliver an event before giving up on it
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+}
+
+fn default_max_attempts() -> u32 {
+    8
+}
+
+fn deserialize_regex<'de, D>(deserializer: D) -> Result<Option<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let pattern = String::deserialize(deserializer)?;
+
+    Regex::new(&pattern)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+impl Webhook {
+    fn matches(&self, event: &MachineEvent) -> bool {
+        (self.events.is_empty() || self.events.contains(&event.event.event_type()))
+            && (self.kinds.is_empty() || self.kinds.contains(&event.kind))
+            && (self.locations.is_empty() || self.locations.contains(&event.location))
+            && match &self.name {
+                Some(name) => name.is_match(&event.name),
+                None => true,
+            }
+    }
+}
+
+/// Read the `[[webhook]]` tables of the TOML file at `path`
+pub fn load(path: &Path) -> color_eyre::Result<Vec<Webhook>> {
+    let contents = fs::read_to_string(path)
+        .wrap_err_with(|| format!("failed to read webhooks file {}", path.display()))?;
+
+    let file: WebhooksFile = toml::from_str(&contents)
+        .wrap_err_with(|| format!("invalid webhooks file {}", path.display()))?;
+
+    for (index, webhook) in file.webhook.iter().enumerate() {
+        if webhook.max_attempts == 0 {
+            bail!("webhook[{index}].max_attempts must be at least 1");
+        }
+    }
+
+    Ok(file.webhook)
+}
+
+/// An event serialized once, to be delivered to every webhook it matches
+#[derive(Debug)]
+struct Delivery {
+    event_type: MachineEventType,
+    body: String,
+}
+
+/// Send every event to the webhooks it matches until shutdown
+///
+/// Every webhook gets a queue and worker of its own, so a slow or failing endpoint only ever
+/// holds up its own deliveries.
+pub async fn deliver(
+    webhooks: Vec<Webhook>,
+    events: Events,
+    mut shutdown: Shutdown,
+) -> color_eyre::Result<()> {
+    if webhooks.is_empty() {
+        return Ok(());
+    }
+
+    let http_client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .wrap_err("failed to build webhook http client")?;
+
+    let queues: Vec<(Arc<Webhook>, mpsc::Sender<Arc<Delivery>>)> = webhooks
+        .into_iter()
+        .map(|webhook| {
+            let webhook = Arc::new(webhook);
+            let (sender, receiver) = mpsc::channel(64);
+
+            tokio::spawn(worker(
+                http_client.clone(),
+                Arc::clone(&webhook),
+                receiver,
+                shutdown.clone(),
+            ));
+
+            (webhook, sender)
+        })
+        .collect();
+
+    info!(webhooks = queues.len(), "delivering events to webhooks");
+
+    let mut receiver = events.subscribe();
+
+    loop {
+        let event = tokio::select! {
+            event = receiver.recv() => event,
+            () = shutdown.wait() => return Ok(()),
+        };
+
+        let event = match event {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!(skipped, "webhooks fell behind, some events were not delivered");
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+
+        let delivery = Arc::new(Delivery {
+            event_type: event.event.event_type(),
+            body: serde_json::to_string(&event).wrap_err("failed to serialize event")?,
+        });
+
+        for (webhook, queue) in &queues {
+            if !webhook.matches(&event) {
+                continue;
+            }
+
+            if let Err(TrySendError::Full(_)) = queue.try_send(Arc::clone(&delivery)) {
+                warn!(url = %webhook.url, "webhook queue is full, dropping event");
+            }
+        }
+    }
+}
+
+async fn worker(
+    http_client: reqwest::Client,
+    webhook: Arc<Webhook>,
+    mut deliveries: mpsc::Receiver<Arc<Delivery>>,
+    mut shutdown: Shutdown,
+) {
+    loop {
+        let delivery = tokio::select! {
+            delivery = deliveries.recv() => delivery,
+            () = shutdown.wait() => return,
+        };
+
+        let Some(delivery) = delivery else { return };
+
+        let mut backoff = Backoff::new(Duration::from_secs(5), Duration::from_secs(10 * 60));
+
+        for attempt in 1..=webhook.max_attempts {
+            match send(&http_client, &webhook, &delivery).await {
+                Ok(()) => {
+                    debug!(url = %webhook.url, event = delivery.event_type.as_str(), attempt, "delivered webhook");
+                    break;
+                }
+                Err(error) if attempt == webhook.max_attempts => {
+                    warn!(?error, url = %webhook.url, attempt, "giving up on webhook delivery");
+                }
+                Err(error) => {
+                    let delay = backoff.next_delay();
+
+                    warn!(?error, url = %webhook.url, attempt, ?delay, "webhook delivery failed, retrying");
+
+                    tokio::select! {
+                        () = sleep(delay) => {},
+                        () = shutdown.wait() => return,
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn send(
+    http_client: &reqwest::Client,
+    webhook: &Webhook,
+    delivery: &Delivery,
+) -> color_eyre::Result<()> {
+    post_signed(
+        http_client,
+        &webhook.url,
+        &webhook.secret,
+        delivery.event_type.as_str(),
+        delivery.body.clone(),
+    )
+    .await
+}
+
+/// POST a JSON `body` to `url`, signed with `secret` the same way as every webhook delivery
+pub async fn post_signed(
+    http_client: &reqwest::Client,
+    url: &Url,
+    secret: &Secret,
+    event: &str,
+    body: String,
+) -> color_eyre::Result<()> {
+    let timestamp = unix_timestamp().to_string();
+
+    let mut signature = Hmac::<Sha256>::new_from_slice(secret.expose().as_bytes())
+        .expect("hmac should accept keys of any length");
+    signature.update(timestamp.as_bytes());
+    signature.update(b".");
+    signature.update(body.as_bytes());
+
+    http_client
+        .post(url.clone())
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(SIGNATURE_HEADER, hex::encode(signature.finalize().into_bytes()))
+        .header(TIMESTAMP_HEADER, timestamp)
+        .header(EVENT_HEADER, event)
+        .body(body)
+        .send()
+        .await
+        .wrap_err("failed to POST webhook")?
+        .error_for_status()
+        .wrap_err("webhook responded with non-success status code")?;
+
+    Ok(())
+}